@@ -0,0 +1,260 @@
+use crate::elf::{ELFClass, Endian};
+
+pub const EM_SPARC: u16 = 2;
+pub const EM_386: u16 = 3;
+pub const EM_68K: u16 = 4;
+pub const EM_MIPS: u16 = 8;
+pub const EM_PARISC: u16 = 15;
+pub const EM_SPARC32PLUS: u16 = 18;
+pub const EM_PPC: u16 = 20;
+pub const EM_PPC64: u16 = 21;
+pub const EM_S390: u16 = 22;
+pub const EM_ARM: u16 = 40;
+pub const EM_SH: u16 = 42;
+pub const EM_SPARCV9: u16 = 43;
+pub const EM_X86_64: u16 = 62;
+pub const EM_OPENRISC: u16 = 92;
+pub const EM_ARC_COMPACT: u16 = 93;
+pub const EM_XTENSA: u16 = 94;
+pub const EM_ALTERA_NIOS2: u16 = 113;
+// Hexagon
+pub const EM_QDSP6: u16 = 164;
+pub const EM_AARCH64: u16 = 183;
+pub const EM_MICROBLAZE: u16 = 189;
+pub const EM_ARC_COMPACT2: u16 = 195;
+pub const EM_RISCV: u16 = 243;
+pub const EM_CSKY: u16 = 252;
+pub const EM_LOONGARCH: u16 = 258;
+// Linux uses the unofficial EM_ALPHA value
+pub const EM_ALPHA: u16 = 0x9026;
+// Pre-standard EM_MICROBLAZE value still emitted by older toolchains
+pub const EM_MICROBLAZE_OLD: u16 = 0xbaab;
+
+// n32 ABI marker in e_flags, n32 executables are ELFCLASS32
+pub const EF_MIPS_ABI2: u32 = 0x20;
+
+pub const EF_CSKY_ABIMASK: u32 = 0xf0000000;
+pub const EF_CSKY_ABIV2: u32 = 0x20000000;
+
+pub struct Target {
+    pub machine: u16,
+    pub class: ELFClass,
+    pub endian: Endian,
+    // e_flags & flags_mask must equal flags for the target to match
+    pub flags_mask: u32,
+    pub flags: u32,
+    // qemu-<suffix>
+    pub qemu: &'static str,
+    // Loader a glibc based sysroot is expected to provide
+    pub loader: &'static str,
+    // Library directories relative to the sysroot root and its usr/
+    pub lib_dirs: &'static [&'static str],
+}
+
+macro_rules! target {
+    ($machine:expr, $class:ident, $endian:ident, $qemu:expr, $loader:expr, $lib_dirs:expr) => {
+        target!($machine, $class, $endian, 0, 0, $qemu, $loader, $lib_dirs)
+    };
+    (
+        $machine:expr, $class:ident, $endian:ident, $flags_mask:expr, $flags:expr,
+        $qemu:expr, $loader:expr, $lib_dirs:expr
+    ) => {
+        Target {
+            machine: $machine,
+            class: ELFClass::$class,
+            endian: Endian::$endian,
+            flags_mask: $flags_mask,
+            flags: $flags,
+            qemu: $qemu,
+            loader: $loader,
+            lib_dirs: $lib_dirs,
+        }
+    };
+}
+
+// Every machine qemu-user can emulate. Anything ABI specific which can't be
+// expressed with e_flags (float ABI library directories etc.) is handled on
+// top of this table.
+#[rustfmt::skip]
+pub static TARGETS: &[Target] = &[
+    target!(EM_AARCH64, ELFCLASS64, Little, "aarch64", "/lib/ld-linux-aarch64.so.1", &["lib64"]),
+    target!(EM_AARCH64, ELFCLASS64, Big, "aarch64_be", "/lib/ld-linux-aarch64_be.so.1", &["lib64"]),
+    // ILP32 ABI
+    target!(EM_AARCH64, ELFCLASS32, Little, "aarch64", "/lib/ld-linux-aarch64_ilp32.so.1", &["libilp32"]),
+    target!(EM_AARCH64, ELFCLASS32, Big, "aarch64_be", "/lib/ld-linux-aarch64_be_ilp32.so.1", &["libilp32"]),
+    target!(EM_ALPHA, ELFCLASS64, Little, "alpha", "/lib/ld-linux.so.2", &["lib"]),
+    target!(EM_ARC_COMPACT, ELFCLASS32, Little, "arc", "/lib/ld-linux-arc.so.2", &["lib"]),
+    target!(EM_ARC_COMPACT, ELFCLASS32, Big, "arceb", "/lib/ld-linux-arceb.so.2", &["lib"]),
+    target!(EM_ARC_COMPACT2, ELFCLASS32, Little, "arc", "/lib/ld-linux-arc.so.2", &["lib"]),
+    target!(EM_ARC_COMPACT2, ELFCLASS32, Big, "arceb", "/lib/ld-linux-arceb.so.2", &["lib"]),
+    target!(EM_ARM, ELFCLASS32, Little, "arm", "/lib/ld-linux-armhf.so.3", &["lib"]),
+    target!(EM_ARM, ELFCLASS32, Big, "armeb", "/lib/ld-linux-armhf.so.3", &["lib"]),
+    target!(EM_CSKY, ELFCLASS32, Little, EF_CSKY_ABIMASK, EF_CSKY_ABIV2, "cskyv2", "/lib/ld-linux-cskyv2.so.1", &["lib"]),
+    target!(EM_QDSP6, ELFCLASS32, Little, "hexagon", "/lib/ld-musl-hexagon.so.1", &["lib"]),
+    target!(EM_PARISC, ELFCLASS32, Big, "hppa", "/lib/ld.so.1", &["lib"]),
+    target!(EM_386, ELFCLASS32, Little, "i386", "/lib/ld-linux.so.2", &["lib"]),
+    target!(EM_LOONGARCH, ELFCLASS64, Little, "loongarch64", "/lib64/ld-linux-loongarch-lp64d.so.1", &["lib64"]),
+    target!(EM_68K, ELFCLASS32, Big, "m68k", "/lib/ld.so.1", &["lib"]),
+    target!(EM_MICROBLAZE, ELFCLASS32, Big, "microblaze", "/lib/ld.so.1", &["lib"]),
+    target!(EM_MICROBLAZE, ELFCLASS32, Little, "microblazeel", "/lib/ld.so.1", &["lib"]),
+    target!(EM_MICROBLAZE_OLD, ELFCLASS32, Big, "microblaze", "/lib/ld.so.1", &["lib"]),
+    target!(EM_MICROBLAZE_OLD, ELFCLASS32, Little, "microblazeel", "/lib/ld.so.1", &["lib"]),
+    target!(EM_MIPS, ELFCLASS32, Big, EF_MIPS_ABI2, 0, "mips", "/lib/ld.so.1", &["lib"]),
+    target!(EM_MIPS, ELFCLASS32, Little, EF_MIPS_ABI2, 0, "mipsel", "/lib/ld.so.1", &["lib"]),
+    target!(EM_MIPS, ELFCLASS32, Big, EF_MIPS_ABI2, EF_MIPS_ABI2, "mipsn32", "/lib32/ld.so.1", &["lib32"]),
+    target!(EM_MIPS, ELFCLASS32, Little, EF_MIPS_ABI2, EF_MIPS_ABI2, "mipsn32el", "/lib32/ld.so.1", &["lib32"]),
+    target!(EM_MIPS, ELFCLASS64, Big, "mips64", "/lib64/ld.so.1", &["lib64"]),
+    target!(EM_MIPS, ELFCLASS64, Little, "mips64el", "/lib64/ld.so.1", &["lib64"]),
+    target!(EM_ALTERA_NIOS2, ELFCLASS32, Little, "nios2", "/lib/ld-linux-nios2.so.1", &["lib"]),
+    target!(EM_OPENRISC, ELFCLASS32, Big, "or1k", "/lib/ld-linux-or1k.so.1", &["lib"]),
+    target!(EM_PPC, ELFCLASS32, Big, "ppc", "/lib/ld.so.1", &["lib"]),
+    target!(EM_PPC64, ELFCLASS64, Big, "ppc64", "/lib64/ld64.so.1", &["lib64"]),
+    target!(EM_PPC64, ELFCLASS64, Little, "ppc64le", "/lib64/ld64.so.2", &["lib64"]),
+    target!(EM_RISCV, ELFCLASS32, Little, "riscv32", "/lib/ld-linux-riscv32-ilp32d.so.1", &["lib32", "lib"]),
+    target!(EM_RISCV, ELFCLASS64, Little, "riscv64", "/lib/ld-linux-riscv64-lp64d.so.1", &["lib64"]),
+    target!(EM_S390, ELFCLASS64, Big, "s390x", "/lib/ld64.so.1", &["lib64"]),
+    target!(EM_SH, ELFCLASS32, Little, "sh4", "/lib/ld-linux.so.2", &["lib"]),
+    target!(EM_SH, ELFCLASS32, Big, "sh4eb", "/lib/ld-linux.so.2", &["lib"]),
+    target!(EM_SPARC, ELFCLASS32, Big, "sparc", "/lib/ld-linux.so.2", &["lib"]),
+    target!(EM_SPARC32PLUS, ELFCLASS32, Big, "sparc32plus", "/lib/ld-linux.so.2", &["lib"]),
+    target!(EM_SPARCV9, ELFCLASS64, Big, "sparc64", "/lib64/ld-linux.so.2", &["lib64"]),
+    target!(EM_X86_64, ELFCLASS64, Little, "x86_64", "/lib64/ld-linux-x86-64.so.2", &["lib64"]),
+    // x32 ABI
+    target!(EM_X86_64, ELFCLASS32, Little, "x86_64", "/libx32/ld-linux-x32.so.2", &["libx32"]),
+    target!(EM_XTENSA, ELFCLASS32, Little, "xtensa", "/lib/ld.so.1", &["lib"]),
+    target!(EM_XTENSA, ELFCLASS32, Big, "xtensaeb", "/lib/ld.so.1", &["lib"]),
+];
+
+pub fn find_target(
+    machine: u16,
+    class: ELFClass,
+    endian: Endian,
+    flags: u32,
+) -> Option<&'static Target> {
+    TARGETS.iter().find(|target| {
+        target.machine == machine
+            && target.class == class
+            && target.endian == endian
+            && flags & target.flags_mask == target.flags
+    })
+}
+
+// Used to name the machine when no target matches the class/endianness.
+pub fn machine_name(machine: u16) -> Option<&'static str> {
+    TARGETS
+        .iter()
+        .find(|target| target.machine == machine)
+        .map(|target| target.qemu)
+}
+
+// Map a qemu name or the architecture part of a target triple
+// (aarch64-linux-gnu, armv7l, powerpc64le-unknown-linux-gnu, riscv64gc-unknown-linux-gnu)
+// to a qemu name.
+pub fn qemu_name(arch: &str) -> Option<&'static str> {
+    // x86-64 has its hyphen in the cpu, not before the rest of the triple
+    let (cpu, rest) = match arch.strip_prefix("x86-64") {
+        Some(rest) if rest.is_empty() || rest.starts_with('-') => ("x86-64", rest),
+        _ => arch.split_once('-').unwrap_or((arch, "")),
+    };
+    let n32 = rest.ends_with("abin32");
+
+    let name = match cpu {
+        "arm64" => "aarch64",
+        "amd64" | "x86-64" => "x86_64",
+        "i486" | "i586" | "i686" | "x86" => "i386",
+        "powerpc" => "ppc",
+        "powerpc64" => "ppc64",
+        "powerpc64le" | "ppc64el" => "ppc64le",
+        "mips64" if n32 => "mipsn32",
+        "mips64el" if n32 => "mipsn32el",
+        "sh4a" => "sh4",
+        "or1200" => "or1k",
+        "csky" => "cskyv2",
+        "armhf" | "armel" => "arm",
+        cpu if cpu.starts_with("armv") && cpu.ends_with('b') => "armeb",
+        cpu if cpu.starts_with("armv") => "arm",
+        // Rust's thumbv7neon-unknown-linux-gnueabihf runs on qemu-arm
+        cpu if cpu.starts_with("thumbv") => "arm",
+        // riscv64gc, riscv32imac, the letters are ISA extensions
+        cpu if cpu.starts_with("riscv64") => "riscv64",
+        cpu if cpu.starts_with("riscv32") => "riscv32",
+        cpu => cpu,
+    };
+
+    TARGETS
+        .iter()
+        .map(|target| target.qemu)
+        .find(|qemu| *qemu == name)
+}
+
+// Target for a forced qemu name, preferring the row matching the executable.
+pub fn find_by_name(
+    qemu: &str,
+    class: ELFClass,
+    endian: Endian,
+    flags: u32,
+) -> Option<&'static Target> {
+    let mut candidates = TARGETS.iter().filter(|target| target.qemu == qemu);
+    let first = candidates.clone().next();
+
+    candidates
+        .find(|target| {
+            target.class == class
+                && target.endian == endian
+                && flags & target.flags_mask == target.flags
+        })
+        .or(first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::qemu_name;
+
+    #[test]
+    fn triples() {
+        let names = [
+            ("aarch64", "aarch64"),
+            ("arm64", "aarch64"),
+            ("aarch64-linux-gnu", "aarch64"),
+            ("aarch64-unknown-linux-musl", "aarch64"),
+            ("amd64", "x86_64"),
+            ("x86_64-linux-gnu", "x86_64"),
+            ("x86-64", "x86_64"),
+            ("x86-64-linux-gnu", "x86_64"),
+            ("x86", "i386"),
+            ("i686-unknown-linux-gnu", "i386"),
+            ("armv7l", "arm"),
+            ("armhf", "arm"),
+            ("armv7-unknown-linux-gnueabihf", "arm"),
+            ("armv5te-unknown-linux-gnueabi", "arm"),
+            ("armv7b", "armeb"),
+            ("thumbv7neon-unknown-linux-gnueabihf", "arm"),
+            ("thumbv7neon-linux-androideabi", "arm"),
+            ("powerpc-linux-gnu", "ppc"),
+            ("powerpc64-unknown-linux-gnu", "ppc64"),
+            ("powerpc64le-unknown-linux-gnu", "ppc64le"),
+            ("ppc64el", "ppc64le"),
+            ("mips64-linux-gnuabi64", "mips64"),
+            ("mips64-linux-gnuabin32", "mipsn32"),
+            ("mips64el-linux-gnuabin32", "mipsn32el"),
+            ("riscv64", "riscv64"),
+            ("riscv64gc-unknown-linux-gnu", "riscv64"),
+            ("riscv64-linux-gnu", "riscv64"),
+            ("riscv32gc-unknown-linux-gnu", "riscv32"),
+            ("riscv32imac-unknown-linux-musl", "riscv32"),
+            ("sh4a-linux-gnu", "sh4"),
+            ("csky-linux-gnuabiv2", "cskyv2"),
+            ("s390x-linux-gnu", "s390x"),
+        ];
+        for (triple, name) in names {
+            assert_eq!(qemu_name(triple), Some(name), "{}", triple);
+        }
+    }
+
+    #[test]
+    fn unknown() {
+        assert_eq!(qemu_name("wasm32-unknown-unknown"), None);
+        assert_eq!(qemu_name("nvptx64-nvidia-cuda"), None);
+        assert_eq!(qemu_name(""), None);
+    }
+}
@@ -0,0 +1,169 @@
+// Sysroots kept as a tarball or squashfs image. Tarballs are unpacked into
+// the cache and squashfs images are mounted with squashfuse (or unpacked
+// without it), keyed by the image's checksum so a new image replaces the old
+// copy.
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::fetch;
+use crate::foreign;
+
+// $XDG_CACHE_HOME/emu/images.toml, so an unchanged image isn't checksummed
+// on every run:
+//
+//   ["/srv/ci/rootfs-arm64.tar.zst"]
+//   size = 73400320
+//   mtime = 1718000000
+//   sha256 = "9f86d0..."
+#[derive(Clone, Deserialize, Serialize)]
+struct Stamp {
+    size: u64,
+    mtime: i64,
+    sha256: String,
+}
+
+const TARBALLS: &[&str] = &[
+    ".tar", ".tar.gz", ".tgz", ".tar.xz", ".txz", ".tar.zst", ".tzst", ".tar.bz2",
+];
+const SQUASHFS: &[&str] = &[".squashfs", ".sqfs", ".sfs"];
+
+fn has_suffix(path: &Path, suffixes: &[&str]) -> bool {
+    let name = path.to_string_lossy();
+    suffixes.iter().any(|suffix| name.ends_with(suffix))
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    path.is_file() && (has_suffix(path, TARBALLS) || has_suffix(path, SQUASHFS))
+}
+
+fn stamps_path() -> PathBuf {
+    fetch::cache_dir().join("images.toml")
+}
+
+fn load_stamps() -> BTreeMap<String, Stamp> {
+    fs::read_to_string(stamps_path())
+        .ok()
+        .and_then(|contents| toml::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+pub fn sha256(path: &Path) -> String {
+    let output = Command::new("sha256sum")
+        .arg(path)
+        .output()
+        .unwrap_or_else(|e| panic!("Unable to run sha256sum: {}, is it installed?", e));
+    if !output.status.success() {
+        panic!("Unable to checksum {}.", path.display());
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn is_mounted(dir: &Path) -> bool {
+    let dir = dir.to_string_lossy();
+    fs::read_to_string("/proc/self/mountinfo")
+        .unwrap_or_default()
+        .lines()
+        .any(|line| line.split(' ').nth(4) == Some(&*dir))
+}
+
+fn unpack(image: &Path, dir: &Path) {
+    info!("unpacking {} into {}", image.display(), dir.display());
+    fetch::install(dir, |partial| {
+        if has_suffix(image, SQUASHFS) {
+            let mut unsquashfs = Command::new("unsquashfs");
+            unsquashfs
+                .args(["-f", "-no-xattrs", "-d"])
+                .arg(partial)
+                .arg(image);
+            fetch::run(unsquashfs);
+        } else {
+            fetch::extract(image, partial);
+        }
+    });
+}
+
+fn images_dir() -> PathBuf {
+    fetch::cache_dir().join("sysroots")
+}
+
+// Where prepare put image the last time, without checking the image again
+pub fn prepared_dir(image: &Path) -> Option<PathBuf> {
+    fetch::cache_home()?;
+    let image = fs::canonicalize(image).ok()?;
+    let stamp = load_stamps().remove(&*image.to_string_lossy())?;
+    Some(images_dir().join(&stamp.sha256[..16.min(stamp.sha256.len())]))
+}
+
+// The directory to use as the sysroot for image, unpacked or mounted first
+// when the image is new or has changed
+pub fn prepare(image: &Path) -> PathBuf {
+    let image = fs::canonicalize(image)
+        .unwrap_or_else(|e| panic!("Unable to read {}: {}", image.display(), e));
+    let metadata = fs::metadata(&image)
+        .unwrap_or_else(|e| panic!("Unable to read {}: {}", image.display(), e));
+    let key = image.to_string_lossy().to_string();
+
+    let mut stamps = load_stamps();
+    let previous = stamps.get(&key).cloned();
+    let sha256 = match &previous {
+        Some(stamp) if stamp.size == metadata.size() && stamp.mtime == metadata.mtime() => {
+            stamp.sha256.clone()
+        }
+        _ => sha256(&image),
+    };
+
+    let images = images_dir();
+    let dir = images.join(&sha256[..16.min(sha256.len())]);
+
+    // The copy of an older version of the image is no longer needed
+    if let Some(previous) = previous.filter(|stamp| stamp.sha256 != sha256) {
+        let old = images.join(&previous.sha256[..16.min(previous.sha256.len())]);
+        if is_mounted(&old) {
+            let _ = Command::new("fusermount")
+                .arg("-u")
+                .arg(&old)
+                .stderr(fetch::stderr())
+                .output();
+        }
+        if !is_mounted(&old) {
+            let _ = fs::remove_dir_all(&old);
+        }
+    }
+
+    if has_suffix(&image, SQUASHFS) && foreign::in_path("squashfuse") {
+        if !is_mounted(&dir) {
+            fs::create_dir_all(&dir)
+                .unwrap_or_else(|e| panic!("Unable to create {}: {}", dir.display(), e));
+            let mut squashfuse = Command::new("squashfuse");
+            squashfuse.arg(&image).arg(&dir);
+            fetch::run(squashfuse);
+            info!("mounted {} on {}", image.display(), dir.display());
+        }
+    } else if !dir.is_dir() {
+        unpack(&image, &dir);
+    }
+
+    stamps.insert(
+        key,
+        Stamp {
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            sha256,
+        },
+    );
+    if let Ok(contents) = toml::to_string(&stamps) {
+        let _ = fs::write(stamps_path(), contents);
+    }
+    dir
+}
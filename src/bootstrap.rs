@@ -0,0 +1,173 @@
+// Sysroots made by the distributions' own bootstrap tools for emu sysroot
+// create. Foreign maintainer scripts are run by the Debian tools through
+// binfmt_misc, which emu's binfmt.d files or qemu-user-binfmt provide.
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::Command;
+
+use crate::arch;
+use crate::cli::Distro;
+use crate::fetch;
+use crate::foreign;
+use crate::report;
+use crate::sysroot;
+use crate::sysroot::Origin;
+
+pub fn is_root() -> bool {
+    fs::metadata("/proc/self").is_ok_and(|metadata| metadata.uid() == 0)
+}
+
+fn debian(
+    distro: Distro,
+    qemu: &str,
+    release: &str,
+    mirror: Option<String>,
+    include: &[String],
+    dir: &Path,
+) {
+    let debian_arch = fetch::debian_arch(qemu)
+        .unwrap_or_else(|| panic!("There are no Debian packages for {}.", qemu));
+    let mirror = mirror.unwrap_or_else(|| {
+        String::from(match distro {
+            Distro::Ubuntu if debian_arch == "amd64" || debian_arch == "i386" => {
+                "http://archive.ubuntu.com/ubuntu"
+            }
+            Distro::Ubuntu => "http://ports.ubuntu.com/ubuntu-ports",
+            _ => "http://deb.debian.org/debian",
+        })
+    });
+
+    if foreign::in_path("mmdebstrap") {
+        let mut mmdebstrap = Command::new("mmdebstrap");
+        mmdebstrap
+            .arg("--variant=minbase")
+            .arg(format!("--architectures={}", debian_arch));
+        if !include.is_empty() {
+            mmdebstrap.arg(format!("--include={}", include.join(",")));
+        }
+
+        if is_root() {
+            mmdebstrap.arg("--mode=root").arg(release).arg(dir);
+            mmdebstrap.arg(&mirror);
+            fetch::run(mmdebstrap);
+            return;
+        }
+
+        // In a user namespace the files would belong to subordinate ids,
+        // unpacking a tarball makes them the user's.
+        let archive = fetch::cache_dir().join(format!(
+            "{}.tar",
+            dir.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        if let Some(cache) = archive.parent() {
+            fs::create_dir_all(cache)
+                .unwrap_or_else(|e| panic!("Unable to create {}: {}", cache.display(), e));
+        }
+        mmdebstrap
+            .arg("--mode=unshare")
+            .arg(release)
+            .arg(&archive)
+            .arg(&mirror);
+        fetch::run(mmdebstrap);
+        fetch::extract(&archive, dir);
+        let _ = fs::remove_file(&archive);
+        return;
+    }
+
+    if !foreign::in_path("debootstrap") {
+        panic!("Neither mmdebstrap nor debootstrap is installed, install mmdebstrap.");
+    }
+    if !is_root() {
+        panic!("debootstrap has to run as root, install mmdebstrap to create sysroots as a user.");
+    }
+    let mut debootstrap = Command::new("debootstrap");
+    debootstrap
+        .arg("--variant=minbase")
+        .arg(format!("--arch={}", debian_arch));
+    if !include.is_empty() {
+        debootstrap.arg(format!("--include={}", include.join(",")));
+    }
+    debootstrap.arg(release).arg(dir).arg(&mirror);
+    fetch::run(debootstrap);
+}
+
+// apk-tools installs into any directory, with --no-scripts nothing foreign is
+// run. Alpine's signing keys are only there on Alpine itself.
+fn alpine(qemu: &str, release: &str, mirror: Option<String>, include: &[String], dir: &Path) {
+    let alpine_arch = fetch::alpine_arch(qemu)
+        .unwrap_or_else(|| panic!("There are no Alpine packages for {}.", qemu));
+    let mirror = mirror.unwrap_or_else(|| String::from("https://dl-cdn.alpinelinux.org/alpine"));
+    let branch = fetch::alpine_branch(release);
+
+    let apk = ["apk", "apk.static"]
+        .into_iter()
+        .find(|apk| foreign::in_path(apk))
+        .unwrap_or_else(|| panic!("apk-tools is not installed, apk or apk.static is needed."));
+
+    let mut command = Command::new(apk);
+    command
+        .arg("--root")
+        .arg(dir)
+        .arg("--arch")
+        .arg(alpine_arch)
+        .args(["--initdb", "--no-cache", "--no-scripts"]);
+    for repository in ["main", "community"] {
+        command.arg("--repository").arg(format!(
+            "{}/{}/{}",
+            mirror.trim_end_matches('/'),
+            branch,
+            repository
+        ));
+    }
+    if Path::new("/etc/apk/keys").is_dir() {
+        command.arg("--keys-dir").arg("/etc/apk/keys");
+    } else {
+        report::warning("Alpine's signing keys aren't installed, the packages can't be verified.");
+        command.arg("--allow-untrusted");
+    }
+    command.args(["add", "musl", "libgcc"]).args(include);
+    fetch::run(command);
+}
+
+pub fn create(
+    distro: Distro,
+    arch: &str,
+    release: Option<String>,
+    mirror: Option<String>,
+    include: &[String],
+    name: Option<String>,
+) {
+    let qemu = arch::qemu_name(arch)
+        .unwrap_or_else(|| panic!("{} is not an architecture qemu can emulate.", arch));
+    let (distro_name, default_release) = match distro {
+        Distro::Debian => ("debian", "trixie"),
+        Distro::Ubuntu => ("ubuntu", "noble"),
+        Distro::Alpine => ("alpine", "latest-stable"),
+    };
+    let release = release.unwrap_or_else(|| default_release.to_string());
+
+    let name = name.unwrap_or_else(|| format!("{}-{}-{}", distro_name, release, qemu));
+    let dir = fetch::sysroot_dir(&name);
+
+    let origin = Origin {
+        source: format!(
+            "{} {}{}",
+            distro_name,
+            release,
+            mirror
+                .as_deref()
+                .map(|mirror| format!(" from {}", mirror))
+                .unwrap_or_default()
+        ),
+        fetch: None,
+    };
+    fetch::install(&dir, |partial| match distro {
+        Distro::Debian | Distro::Ubuntu => debian(distro, qemu, &release, mirror, include, partial),
+        Distro::Alpine => alpine(qemu, &release, mirror, include, partial),
+    });
+
+    sysroot::register(&name, &dir, Some(qemu), Some(origin));
+    println!("Added {} as {}.", dir.display(), name);
+}
@@ -0,0 +1,126 @@
+use log::info;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+use crate::config;
+
+// $XDG_STATE_HOME/emu, ~/.local/state when it isn't set
+pub fn state_dir() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        })?;
+    Some(dir.join("emu"))
+}
+
+// $XDG_STATE_HOME/emu/choices.toml, one table per project directory:
+//
+//   ["/home/ismail/src/project"]
+//   "qemu.aarch64" = "/opt/qemu/bin/qemu-aarch64"
+fn state_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("choices.toml"))
+}
+
+// The directory of the project's .emu.toml, otherwise the working directory
+fn project() -> String {
+    config::project_path()
+        .and_then(|path| path.parent().map(PathBuf::from))
+        .or_else(|| env::current_dir().ok())
+        .map(|dir| dir.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn load_state() -> toml::Table {
+    state_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|contents| contents.parse().ok())
+        .unwrap_or_default()
+}
+
+// A value remembered for the project
+pub fn remembered(key: &str) -> Option<String> {
+    load_state()
+        .get(&project())
+        .and_then(|choices| choices.get(key))
+        .and_then(|value| value.as_str())
+        .map(String::from)
+}
+
+pub fn remember(key: &str, value: &str) {
+    let path = match state_path() {
+        Some(path) => path,
+        None => return,
+    };
+
+    let mut state = load_state();
+    let choices = state
+        .entry(project())
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    if let Some(choices) = choices.as_table_mut() {
+        choices.insert(key.to_string(), toml::Value::String(value.to_string()));
+    }
+
+    // Forgetting is harmless, the question is just asked again
+    if let Some(dir) = path.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let _ = fs::write(path, state.to_string());
+}
+
+fn prompt(what: &str, candidates: &[String]) -> Option<usize> {
+    let mut stderr = io::stderr();
+    let _ = writeln!(stderr, "emu: there is more than one {}:", what);
+    for (index, candidate) in candidates.iter().enumerate() {
+        let _ = writeln!(stderr, "  {}) {}", index + 1, candidate);
+    }
+
+    loop {
+        let _ = write!(stderr, "Use which one? [1] ");
+        let _ = stderr.flush();
+
+        let mut answer = String::new();
+        if io::stdin().lock().read_line(&mut answer).ok()? == 0 {
+            return None;
+        }
+        let answer = answer.trim();
+        if answer.is_empty() {
+            return Some(0);
+        }
+        match answer.parse::<usize>() {
+            Ok(number) if (1..=candidates.len()).contains(&number) => return Some(number - 1),
+            _ => {
+                let _ = writeln!(stderr, "Enter a number from 1 to {}.", candidates.len());
+            }
+        }
+    }
+}
+
+// Pick one of candidates (in priority order) for key, e.g. qemu.aarch64. A
+// choice remembered for the project wins, otherwise the user is asked when
+// allowed and there is a terminal to ask on, and the answer is remembered.
+pub fn pick(what: &str, key: &str, candidates: &[String], interactive: bool) -> Option<String> {
+    if candidates.len() < 2 {
+        return candidates.first().cloned();
+    }
+
+    if let Some(choice) = remembered(key).filter(|choice| candidates.contains(choice)) {
+        info!("using {} remembered for this project", choice);
+        return Some(choice);
+    }
+
+    if interactive && io::stdin().is_terminal() && io::stderr().is_terminal() {
+        if let Some(index) = prompt(what, candidates) {
+            remember(key, &candidates[index]);
+            return Some(candidates[index].clone());
+        }
+    }
+
+    info!("picked {} out of {}", candidates[0], candidates.join(", "));
+    Some(candidates[0].clone())
+}
@@ -0,0 +1,410 @@
+use clap::builder::PossibleValuesParser;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand, ValueEnum, ValueHint};
+use clap_complete::Shell;
+
+use std::io;
+
+// binfmt_misc runs "emu <program> <args>", so running a program has to work
+// without naming the run subcommand.
+#[derive(Parser)]
+#[command(version, about = "Run foreign executables with qemu-user")]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run an executable (the default)
+    Run(RunArgs),
+    /// Run an executable under gdb-multiarch or lldb, attached to qemu's gdb
+    /// stub
+    Debug(DebugArgs),
+    /// Run an executable with --strace
+    Strace(RunArgs),
+    /// Show or change settings in the config files
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Manage named sysroots
+    #[command(subcommand)]
+    Sysroot(SysrootCommand),
+    /// Print a shell completion script
+    Completions { shell: Shell },
+}
+
+// Keys are <setting> or arch.<name>.<setting>, e.g. arch.aarch64.sysroot
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective value of a key
+    Get { key: String },
+    /// Set a key, list settings (library-path, qemu-args) take several values
+    Set {
+        /// Change the project's .emu.toml instead of the user's config file
+        #[arg(long)]
+        project: bool,
+        key: String,
+        #[arg(required = true, allow_hyphen_values = true)]
+        values: Vec<String>,
+    },
+    /// Remove a key
+    Unset {
+        /// Change the project's .emu.toml instead of the user's config file
+        #[arg(long)]
+        project: bool,
+        key: String,
+    },
+    /// Print every effective setting and where it came from
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum SysrootCommand {
+    /// Register a directory as a sysroot, usable as --sysroot NAME
+    Add {
+        /// Architecture of the sysroot [default: detected from its loader]
+        #[arg(long, value_name = "ARCH")]
+        arch: Option<String>,
+        name: String,
+        #[arg(value_hint = ValueHint::DirPath)]
+        path: String,
+    },
+    /// Download a prebuilt sysroot and register it
+    Fetch {
+        source: FetchSource,
+        /// Architecture, qemu name or target triple
+        #[arg(long, value_name = "ARCH")]
+        arch: String,
+        /// Release to download [default: trixie, 24.04, latest-stable or stable]
+        #[arg(long, value_name = "RELEASE")]
+        release: Option<String>,
+        /// C library of a Bootlin toolchain (glibc, musl, uclibc) [default: glibc]
+        #[arg(long, value_name = "LIBC")]
+        libc: Option<String>,
+        /// Name to register it as [default: <source>-<release>-<arch>]
+        #[arg(long, value_name = "NAME")]
+        name: Option<String>,
+    },
+    /// Flatten a container image (arm64v8/ubuntu:24.04) into a sysroot with
+    /// podman or docker and register it
+    FromImage {
+        /// Architecture to pull for multi-platform images [default: the
+        /// image's]
+        #[arg(long, value_name = "ARCH")]
+        arch: Option<String>,
+        /// Name to register it as [default: the image with / and : replaced]
+        #[arg(long, value_name = "NAME")]
+        name: Option<String>,
+        image: String,
+    },
+    /// Unpack Debian packages (and their dependencies) into a sysroot and
+    /// register it, maintainer scripts aren't run
+    FromDebs {
+        /// Architecture, qemu name or target triple
+        #[arg(long, value_name = "ARCH")]
+        arch: String,
+        /// Release to take the packages from [default: trixie]
+        #[arg(long, value_name = "RELEASE")]
+        release: Option<String>,
+        /// Archive to download from [default: http://deb.debian.org/debian]
+        #[arg(long, value_name = "URL")]
+        mirror: Option<String>,
+        /// Only unpack the packages given, not their dependencies
+        #[arg(long)]
+        no_deps: bool,
+        /// Name to register it as [default: debs-<release>-<arch>]
+        #[arg(long, value_name = "NAME")]
+        name: Option<String>,
+        /// Package names, or paths of .deb and .ipk files
+        #[arg(required = true)]
+        packages: Vec<String>,
+    },
+    /// Bootstrap a complete sysroot with the distribution's tools and
+    /// register it
+    Create {
+        #[arg(long, value_enum)]
+        distro: Distro,
+        /// Architecture, qemu name or target triple
+        #[arg(long, value_name = "ARCH")]
+        arch: String,
+        /// Release to bootstrap [default: trixie, noble or latest-stable]
+        #[arg(long, value_name = "RELEASE")]
+        release: Option<String>,
+        /// Archive to download from
+        #[arg(long, value_name = "URL")]
+        mirror: Option<String>,
+        /// Extra packages to install, repeat or separate with commas
+        #[arg(long, value_name = "PACKAGE", value_delimiter = ',')]
+        include: Vec<String>,
+        /// Name to register it as [default: <distro>-<release>-<arch>]
+        #[arg(long, value_name = "NAME")]
+        name: Option<String>,
+    },
+    /// List registered sysroots with their architecture and libc, * marks
+    /// the defaults
+    List,
+    /// Report sysroots which changed since they were registered and
+    /// downloaded ones with a newer build available
+    Check { name: Option<String> },
+    /// Forget a sysroot, its files are left alone
+    Remove { name: String },
+    /// Use a sysroot when nothing else names one for its architecture
+    Default { name: String },
+}
+
+#[derive(Args)]
+pub struct RunArgs {
+    /// Sysroot with the executable's libraries, a path or a name from emu
+    /// sysroot list [default: $EMU_SYSROOT]
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub sysroot: Option<String>,
+
+    /// Run as this architecture (qemu name or target triple) instead of the
+    /// one in the ELF header
+    #[arg(long, value_name = "ARCH")]
+    pub arch: Option<String>,
+
+    /// qemu-user binary to use instead of /usr/bin/qemu-<arch> [default: $EMU_QEMU]
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::ExecutablePath)]
+    pub qemu: Option<String>,
+
+    /// Guest address space qemu reserves (-R) [default: $EMU_RESERVED_VA, 0xf7000000]
+    #[arg(long, value_name = "SIZE")]
+    pub reserved_va: Option<String>,
+
+    /// Offset the guest is mapped at (-B) [default: $EMU_GUEST_BASE, 0x1000000000
+    /// for non-PIE executables linked below vm.mmap_min_addr]
+    #[arg(long, value_name = "ADDRESS")]
+    pub guest_base: Option<String>,
+
+    /// qemu -cpu model (cortex-a72, max) instead of the one picked for the
+    /// executable [default: $EMU_CPU_<ARCH>]
+    #[arg(long, value_name = "MODEL")]
+    pub cpu: Option<String>,
+
+    /// Extra argument for qemu, repeat for more (--qemu-arg -d --qemu-arg strace)
+    #[arg(long = "qemu-arg", value_name = "ARG", allow_hyphen_values = true)]
+    pub qemu_args: Vec<String>,
+
+    /// Only use a statically linked qemu, which keeps working in chroots
+    /// and containers
+    #[arg(long, overrides_with = "no_static_qemu")]
+    pub static_qemu: bool,
+
+    /// Only use a dynamically linked qemu
+    #[arg(long, overrides_with = "static_qemu")]
+    pub no_static_qemu: bool,
+
+    /// argv[0] the guest sees instead of the program's path, for multicall
+    /// binaries like busybox. Implies --loader-mode prefix
+    #[arg(long, value_name = "NAME")]
+    pub argv0: Option<String>,
+
+    // The name the program was started as when emu found or resolved it
+    // elsewhere, so busybox style links keep working
+    #[arg(skip)]
+    pub program_name: Option<String>,
+
+    /// Set a variable in the guest, repeat for more
+    #[arg(short = 'e', long = "env", value_name = "NAME=VALUE")]
+    pub env: Vec<String>,
+
+    /// Remove a variable from the guest's environment, repeat for more
+    #[arg(long = "unset-env", value_name = "NAME")]
+    pub unset_env: Vec<String>,
+
+    /// Read NAME=VALUE lines to set in the guest from a file, repeat for more
+    #[arg(long = "env-file", value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub env_files: Vec<String>,
+
+    /// Pass only HOME, PATH, TERM and a few more host variables to the guest
+    #[arg(long)]
+    pub clean_env: bool,
+
+    /// Host variable --clean-env also passes on, repeat for more
+    #[arg(long = "keep-env", value_name = "NAME")]
+    pub keep_env: Vec<String>,
+
+    /// Dynamic loader to run the executable with instead of its interpreter in
+    /// the sysroot, a host path
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    pub ld: Option<String>,
+
+    /// Extra library directory searched after the sysroot's, repeat for more,
+    /// =DIR is inside the sysroot
+    #[arg(short = 'L', long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub library_path: Vec<String>,
+
+    /// How the sysroot's loader is started, prefix keeps argv[0] and
+    /// /proc/self/exe of the guest intact
+    #[arg(long, value_enum, value_name = "MODE")]
+    pub loader_mode: Option<LoaderMode>,
+
+    /// What runs the executable, proot makes the sysroot the guest's / so
+    /// absolute paths like /etc/ssl/certs are found in it
+    #[arg(long, value_enum, value_name = "BACKEND", default_value_t = Backend::Qemu)]
+    pub backend: Backend,
+
+    /// Run on a writable overlay of the sysroot, thrown away afterwards or
+    /// kept as NAME, implies --loader-mode prefix
+    #[arg(long, value_name = "NAME", num_args = 0..=1, require_equals = true)]
+    pub overlay: Option<Option<String>>,
+
+    /// Start qemu's gdb stub on PORT [default: 1234] and wait for gdb to
+    /// connect, implies --loader-mode prefix
+    #[arg(
+        long,
+        value_name = "PORT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = crate::debug::DEFAULT_PORT
+    )]
+    pub gdb: Option<u16>,
+
+    /// qemu's -d logging (--qemu-log in_asm,cpu,page), written to a new file
+    /// in the log directory for every run
+    #[arg(long, value_name = "ITEMS")]
+    pub qemu_log: Option<String>,
+
+    /// Directory for the --qemu-log files [default: $XDG_STATE_HOME/emu/qemu-logs]
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub qemu_log_dir: Option<String>,
+
+    /// Trace the guest's syscalls, or only the ones given (--strace=openat,read)
+    #[arg(
+        long,
+        value_name = "SYSCALLS",
+        num_args = 0..=1,
+        require_equals = true,
+        value_delimiter = ','
+    )]
+    pub strace: Option<Vec<String>>,
+
+    /// File the --strace trace is written to, - for stderr [default:
+    /// <program>.strace, or the --qemu-log file]
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub strace_output: Option<String>,
+
+    /// Profile from the config files to use [default: $EMU_PROFILE]
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Explain what emu detects and picks, repeat for more detail [default: $EMU_LOG]
+    #[arg(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Print nothing but errors, for use as a test runner
+    #[arg(short, long, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Don't ask when several qemu binaries or sysroots fit, use the first one
+    #[arg(long)]
+    pub no_interactive: bool,
+
+    /// Print the command emu would run instead of running it
+    #[arg(long, visible_alias = "print-cmd")]
+    pub dry_run: bool,
+
+    /// How --dry-run output, errors and warnings are written
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    /// Run shared libraries which can also be executed (ld.so, libc.so.6)
+    #[arg(long)]
+    pub allow_shared: bool,
+
+    /// Executable to run followed by its arguments, - reads it from stdin
+    #[arg(
+        value_name = "PROGRAM",
+        required = true,
+        trailing_var_arg = true,
+        value_hint = ValueHint::CommandWithArguments
+    )]
+    pub command: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct DebugArgs {
+    /// Debugger to attach
+    #[arg(long, value_enum, default_value_t = Debugger::Gdb)]
+    pub debugger: Debugger,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum FetchSource {
+    /// Debian images from images.linuxcontainers.org
+    Debian,
+    /// Ubuntu base tarballs
+    Ubuntu,
+    /// Alpine minirootfs (musl)
+    Alpine,
+    /// Sysroots of toolchains.bootlin.com toolchains
+    Bootlin,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Distro {
+    /// mmdebstrap, or debootstrap as root
+    Debian,
+    /// mmdebstrap, or debootstrap as root
+    Ubuntu,
+    /// apk-tools, a small musl sysroot
+    Alpine,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Backend {
+    /// qemu-user with the sysroot's loader and libraries
+    Qemu,
+    /// proot -R <sysroot> with qemu as the translator
+    Proot,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Debugger {
+    /// gdb-multiarch, or gdb when only that is installed
+    Gdb,
+    Lldb,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum LoaderMode {
+    /// qemu runs the loader, which is given the executable
+    Explicit,
+    /// qemu -L looks up every absolute path in the sysroot first, so it
+    /// finds the loader itself and the guest can exec other executables
+    Prefix,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+// Profiles only exist in the config files, the script offers the ones
+// defined when it was generated.
+pub fn print_completions(shell: Shell, profiles: Vec<String>) {
+    let mut command = Cli::command();
+    if !profiles.is_empty() {
+        let parser = PossibleValuesParser::new(profiles);
+        command = command
+            .mut_arg("profile", |arg| arg.value_parser(parser.clone()))
+            .mut_subcommand("run", |run| {
+                run.mut_arg("profile", |arg| arg.value_parser(parser.clone()))
+            })
+            .mut_subcommand("debug", |debug| {
+                debug.mut_arg("profile", |arg| arg.value_parser(parser.clone()))
+            })
+            .mut_subcommand("strace", |strace| {
+                strace.mut_arg("profile", |arg| arg.value_parser(parser))
+            });
+    }
+    clap_complete::generate(shell, &mut command, "emu", &mut io::stdout());
+}
@@ -0,0 +1,600 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::arch;
+use crate::cli::ConfigCommand;
+
+// ~/.config/emu/config.toml and the project's .emu.toml, everything in them
+// is optional:
+//
+//   sysroot = "/usr/lib/sysroots/aarch64"
+//
+//   [arch.riscv64]
+//   sysroot = "/srv/riscv64"
+//   qemu = "/opt/qemu/bin/qemu-riscv64"
+//   qemu-args = ["-cpu", "rv64,v=true"]
+//
+//   [profile.alpine-riscv]
+//   sysroot = "/srv/alpine-riscv64"
+//   cpu = "rv64,v=true"
+//
+// Top level settings apply to every architecture, [arch.<name>] sections
+// override them for one and a profile selected with --profile overrides both.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    #[serde(flatten)]
+    pub defaults: Settings,
+    // Keyed by qemu name or target triple, like --arch
+    pub arch: BTreeMap<String, Settings>,
+    pub profile: BTreeMap<String, Settings>,
+}
+
+// Unset values are skipped when serialized for emu config list
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sysroot: Option<String>,
+    // Used instead of sysroot for Android (bionic) executables
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub android_sysroot: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qemu: Option<String>,
+    // Loader inside the sysroot, instead of the executable's PT_INTERP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loader: Option<String>,
+    // explicit or prefix, like --loader-mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loader_mode: Option<String>,
+    // Host directories added to the end of the library path
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub library_path: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub qemu_args: Vec<String>,
+    // Only statically linked qemu binaries, or only dynamically linked ones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub static_qemu: Option<bool>,
+    // -cpu model instead of the one picked from the executable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<String>,
+    // Where --qemu-log writes, and how large the logs there may get
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qemu_log_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qemu_log_size: Option<String>,
+    // qemu -R and -B, like --reserved-va and --guest-base
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserved_va: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guest_base: Option<String>,
+    // Variables set in the guest
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    // Only pass the allowed host variables on to the guest, like --clean-env
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clean_env: Option<bool>,
+    // Host variables kept by clean-env on top of the usual ones
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub keep_env: Vec<String>,
+}
+
+impl Settings {
+    // Values set in other win, lists are appended
+    pub fn merge(&mut self, other: &Settings) {
+        if other.sysroot.is_some() {
+            self.sysroot.clone_from(&other.sysroot);
+        }
+        if other.android_sysroot.is_some() {
+            self.android_sysroot.clone_from(&other.android_sysroot);
+        }
+        if other.qemu.is_some() {
+            self.qemu.clone_from(&other.qemu);
+        }
+        if other.loader.is_some() {
+            self.loader.clone_from(&other.loader);
+        }
+        if other.loader_mode.is_some() {
+            self.loader_mode.clone_from(&other.loader_mode);
+        }
+        if other.static_qemu.is_some() {
+            self.static_qemu = other.static_qemu;
+        }
+        if other.cpu.is_some() {
+            self.cpu.clone_from(&other.cpu);
+        }
+        if other.reserved_va.is_some() {
+            self.reserved_va.clone_from(&other.reserved_va);
+        }
+        if other.guest_base.is_some() {
+            self.guest_base.clone_from(&other.guest_base);
+        }
+        if other.qemu_log_dir.is_some() {
+            self.qemu_log_dir.clone_from(&other.qemu_log_dir);
+        }
+        if other.qemu_log_size.is_some() {
+            self.qemu_log_size.clone_from(&other.qemu_log_size);
+        }
+        self.library_path.extend(other.library_path.iter().cloned());
+        self.qemu_args.extend(other.qemu_args.iter().cloned());
+        self.env.extend(other.env.clone());
+        if other.clean_env.is_some() {
+            self.clean_env = other.clean_env;
+        }
+        self.keep_env.extend(other.keep_env.iter().cloned());
+    }
+}
+
+impl Config {
+    // Settings for a qemu name, None before the architecture is known
+    pub fn settings(&self, qemu: Option<&str>, profile: Option<&str>) -> Settings {
+        let mut settings = self.defaults.clone();
+        if let Some(section) = qemu.and_then(|qemu| self.arch.get(qemu)) {
+            settings.merge(section);
+        }
+        if let Some(name) = profile {
+            match self.profile.get(name) {
+                Some(profile) => settings.merge(profile),
+                None => panic!("{} is not a profile in the config files.", name),
+            }
+        }
+        settings
+    }
+
+    fn merge(&mut self, other: Config) {
+        self.defaults.merge(&other.defaults);
+        for (name, section) in other.arch {
+            self.arch.entry(name).or_default().merge(&section);
+        }
+        for (name, profile) in other.profile {
+            self.profile.entry(name).or_default().merge(&profile);
+        }
+    }
+}
+
+// $XDG_CONFIG_HOME/emu/config.toml, ~/.config when it isn't set
+pub fn path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join("emu").join("config.toml"))
+}
+
+// The closest .emu.toml in the working directory or one of its parents
+pub fn project_path() -> Option<PathBuf> {
+    env::current_dir()
+        .ok()?
+        .ancestors()
+        .map(|dir| dir.join(".emu.toml"))
+        .find(|path| path.is_file())
+}
+
+// Relative paths in a project file are relative to the directory it is in,
+// so it works from any subdirectory.
+// =dir library paths are inside the sysroot and stay as they are
+fn rebase(path: &mut String, dir: &Path) {
+    if !path.starts_with('/') && !path.starts_with('=') {
+        *path = dir.join(&*path).to_string_lossy().to_string();
+    }
+}
+
+fn read(path: &Path) -> Config {
+    debug!("reading {}", path.display());
+    let contents = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Unable to read {}: {}", path.display(), e));
+    let mut config: Config = toml::from_str(&contents).unwrap_or_else(|e| {
+        panic!(
+            "{} is not a valid configuration file: {}",
+            path.display(),
+            e
+        )
+    });
+
+    // Sections are stored under the qemu name so arm64 and aarch64 are one
+    config.arch = std::mem::take(&mut config.arch)
+        .into_iter()
+        .map(|(name, section)| match arch::qemu_name(&name) {
+            Some(qemu) => (qemu.to_string(), section),
+            None => panic!(
+                "{}: [arch.{}] is not an architecture qemu can emulate.",
+                path.display(),
+                name
+            ),
+        })
+        .collect();
+
+    config
+}
+
+// The files which exist, in the order they apply
+fn files() -> Vec<(PathBuf, Config)> {
+    let mut files = Vec::new();
+
+    if let Some(path) = path().filter(|path| path.exists()) {
+        let config = read(&path);
+        files.push((path, config));
+    }
+
+    if let Some(path) = project_path() {
+        let mut project = read(&path);
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        for settings in std::iter::once(&mut project.defaults)
+            .chain(project.arch.values_mut())
+            .chain(project.profile.values_mut())
+        {
+            settings
+                .sysroot
+                .iter_mut()
+                .for_each(|path| rebase(path, dir));
+            settings
+                .android_sysroot
+                .iter_mut()
+                .for_each(|path| rebase(path, dir));
+            settings
+                .library_path
+                .iter_mut()
+                .for_each(|path| rebase(path, dir));
+            settings
+                .qemu_log_dir
+                .iter_mut()
+                .for_each(|path| rebase(path, dir));
+            // A bare qemu name is looked up in PATH
+            settings
+                .qemu
+                .iter_mut()
+                .filter(|path| path.contains('/'))
+                .for_each(|path| rebase(path, dir));
+        }
+        files.push((path, project));
+    }
+
+    files
+}
+
+// The user's config file with the project's .emu.toml on top
+pub fn load() -> Config {
+    let mut config = Config::default();
+    for (_, file) in files() {
+        config.merge(file);
+    }
+    config
+}
+
+// Every sysroot the config files set for a qemu name, in the order settings()
+// prefers them: [arch] sections before the top level, the project first.
+pub fn sysroot_candidates(qemu: &str) -> Vec<String> {
+    let files = files();
+    let arch = files
+        .iter()
+        .rev()
+        .filter_map(|(_, config)| config.arch.get(qemu)?.sysroot.clone());
+    let defaults = files
+        .iter()
+        .rev()
+        .filter_map(|(_, config)| config.defaults.sysroot.clone());
+
+    let mut candidates = Vec::new();
+    for sysroot in arch.chain(defaults) {
+        if !candidates.contains(&sysroot) {
+            candidates.push(sysroot);
+        }
+    }
+    candidates
+}
+
+enum Kind {
+    Value,
+    // true or false
+    Flag,
+    List,
+    // env.<NAME>
+    Table,
+}
+
+// Every setting emu config accepts
+static KEYS: &[(&str, Kind)] = &[
+    ("sysroot", Kind::Value),
+    ("android-sysroot", Kind::Value),
+    ("qemu", Kind::Value),
+    ("loader", Kind::Value),
+    ("loader-mode", Kind::Value),
+    ("library-path", Kind::List),
+    ("qemu-args", Kind::List),
+    ("static-qemu", Kind::Flag),
+    ("cpu", Kind::Value),
+    ("reserved-va", Kind::Value),
+    ("guest-base", Kind::Value),
+    ("qemu-log-dir", Kind::Value),
+    ("qemu-log-size", Kind::Value),
+    ("env", Kind::Table),
+    ("clean-env", Kind::Flag),
+    ("keep-env", Kind::List),
+];
+
+// [arch.<name>.|profile.<name>.]<setting>[.<NAME>] split into the tables it
+// is stored under, arch names are left as written.
+fn parse_key(key: &str) -> (Vec<&str>, &'static Kind) {
+    let parts: Vec<&str> = key.split('.').collect();
+    let (section, rest) = match parts.as_slice() {
+        ["arch", arch, rest @ ..] if !rest.is_empty() => {
+            if arch::qemu_name(arch).is_none() {
+                panic!("{} is not an architecture qemu can emulate.", arch);
+            }
+            (&parts[..2], rest)
+        }
+        ["profile", _, rest @ ..] if !rest.is_empty() => (&parts[..2], rest),
+        rest => (&parts[..0], rest),
+    };
+
+    let kind = match KEYS.iter().find(|(name, _)| Some(name) == rest.first()) {
+        Some((_, kind @ Kind::Table)) if rest.len() == 2 => kind,
+        Some((_, kind)) if rest.len() == 1 && !matches!(kind, Kind::Table) => kind,
+        _ => panic!(
+            "{} is not a valid key, settings are {} (env.<NAME> for env) optionally under arch.<name>. or profile.<name>.",
+            key,
+            KEYS.iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+
+    (section.iter().chain(rest).copied().collect(), kind)
+}
+
+// Effective values and where they came from, keyed like emu config get
+fn effective() -> BTreeMap<String, (toml::Value, Vec<String>)> {
+    let mut entries: BTreeMap<String, (toml::Value, Vec<String>)> = BTreeMap::new();
+    let mut add =
+        |key: String, value: toml::Value, source: String| match (entries.get_mut(&key), &value) {
+            (Some((toml::Value::Array(values), sources)), toml::Value::Array(more)) => {
+                values.extend(more.iter().cloned());
+                sources.push(source);
+            }
+            _ => {
+                entries.insert(key, (value, vec![source]));
+            }
+        };
+
+    for (path, config) in files() {
+        let sections = std::iter::once((String::new(), &config.defaults))
+            .chain(
+                config
+                    .arch
+                    .iter()
+                    .map(|(name, settings)| (format!("arch.{}.", name), settings)),
+            )
+            .chain(
+                config
+                    .profile
+                    .iter()
+                    .map(|(name, settings)| (format!("profile.{}.", name), settings)),
+            );
+        for (prefix, settings) in sections {
+            let table = toml::Table::try_from(settings).unwrap();
+            for (name, value) in table {
+                match value {
+                    toml::Value::Table(variables) => {
+                        for (variable, value) in variables {
+                            add(
+                                format!("{}{}.{}", prefix, name, variable),
+                                value,
+                                path.display().to_string(),
+                            );
+                        }
+                    }
+                    value => add(
+                        format!("{}{}", prefix, name),
+                        value,
+                        path.display().to_string(),
+                    ),
+                }
+            }
+        }
+    }
+
+    // Environment variables which take precedence over the files
+    for (variable, key) in [("EMU_SYSROOT", "sysroot"), ("EMU_QEMU", "qemu")] {
+        if let Ok(value) = env::var(variable) {
+            add(
+                key.to_string(),
+                toml::Value::String(value),
+                variable.to_string(),
+            );
+        }
+    }
+    let qemu_args = |value: String| {
+        toml::Value::Array(
+            value
+                .split_whitespace()
+                .map(|arg| toml::Value::String(arg.to_string()))
+                .collect(),
+        )
+    };
+    if let Ok(value) = env::var("EMU_QEMU_ARGS") {
+        add(
+            String::from("qemu-args"),
+            qemu_args(value),
+            String::from("EMU_QEMU_ARGS"),
+        );
+    }
+    // Several targets share a qemu name
+    let names: BTreeSet<&str> = arch::TARGETS.iter().map(|target| target.qemu).collect();
+    for name in names {
+        let variable = format!("EMU_SYSROOT_{}", name.to_uppercase());
+        if let Ok(value) = env::var(&variable) {
+            add(
+                format!("arch.{}.sysroot", name),
+                toml::Value::String(value),
+                variable,
+            );
+        }
+
+        let variable = format!("EMU_QEMU_ARGS_{}", name.to_uppercase());
+        if let Ok(value) = env::var(&variable) {
+            add(
+                format!("arch.{}.qemu-args", name),
+                qemu_args(value),
+                variable,
+            );
+        }
+    }
+
+    entries
+}
+
+fn get(key: &str) {
+    let (mut path, _) = parse_key(key);
+    // Stored under the qemu name
+    if path[0] == "arch" {
+        path[1] = arch::qemu_name(path[1]).unwrap();
+    }
+    let key = path.join(".");
+
+    match effective().get(&key) {
+        Some((toml::Value::String(value), _)) => println!("{}", value),
+        // One per line so arguments with spaces survive
+        Some((toml::Value::Array(values), _)) => {
+            for value in values {
+                println!("{}", value.as_str().unwrap_or_default());
+            }
+        }
+        Some((value, _)) => println!("{}", value),
+        // Like git config, unset keys are only reported by the exit status
+        None => process::exit(1),
+    }
+}
+
+fn list() {
+    let entries = effective();
+
+    // Top level keys come before the arch sections and profiles
+    let mut keys: Vec<&String> = entries.keys().collect();
+    keys.sort_by_key(|key| {
+        (
+            key.starts_with("arch.") || key.starts_with("profile."),
+            *key,
+        )
+    });
+
+    for key in keys {
+        let (value, sources) = &entries[key];
+        println!("{} = {}  # {}", key, value, sources.join(", "));
+    }
+}
+
+// The file set and unset edit, created if it doesn't exist yet
+fn target_file(project: bool) -> PathBuf {
+    if project {
+        return project_path().unwrap_or_else(|| {
+            env::current_dir()
+                .expect("Unable to get the working directory")
+                .join(".emu.toml")
+        });
+    }
+    path().expect("Neither XDG_CONFIG_HOME nor HOME is set, there is no config file.")
+}
+
+fn edit(project: bool, apply: impl FnOnce(&mut toml_edit::DocumentMut, &Path)) {
+    let path = target_file(project);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => panic!("Unable to read {}: {}", path.display(), e),
+    };
+    let mut document: toml_edit::DocumentMut = contents.parse().unwrap_or_else(|e| {
+        panic!(
+            "{} is not a valid configuration file: {}",
+            path.display(),
+            e
+        )
+    });
+
+    apply(&mut document, &path);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .unwrap_or_else(|e| panic!("Unable to create {}: {}", dir.display(), e));
+    }
+    fs::write(&path, document.to_string())
+        .unwrap_or_else(|e| panic!("Unable to write {}: {}", path.display(), e));
+}
+
+fn set(project: bool, key: &str, values: &[String]) {
+    let (path, kind) = parse_key(key);
+    let value = match kind {
+        Kind::List => toml_edit::value(values.iter().collect::<toml_edit::Array>()),
+        _ if values.len() > 1 => panic!("{} takes a single value.", key),
+        Kind::Flag => match values[0].as_str() {
+            "true" => toml_edit::value(true),
+            "false" => toml_edit::value(false),
+            value => panic!("{} is true or false, not {}.", key, value),
+        },
+        _ => toml_edit::value(&values[0]),
+    };
+
+    edit(project, |document, _| {
+        let (name, tables) = path.split_last().unwrap();
+        let mut item = document.as_item_mut();
+        for table in tables {
+            item = item[*table].or_insert(toml_edit::table());
+            // [arch.<name>] headers without an empty [arch] above them
+            if let Some(table) = item.as_table_mut() {
+                table.set_implicit(true);
+            }
+        }
+        item[*name] = value;
+    });
+}
+
+// Remove path from table and the tables it leaves empty, false if it wasn't set
+fn remove(table: &mut dyn toml_edit::TableLike, path: &[&str]) -> bool {
+    let (name, rest) = path.split_first().unwrap();
+    if rest.is_empty() {
+        return table.remove(name).is_some();
+    }
+
+    let removed = table
+        .get_mut(name)
+        .and_then(|item| item.as_table_like_mut())
+        .is_some_and(|inner| remove(inner, rest));
+    if table
+        .get(name)
+        .and_then(|item| item.as_table_like())
+        .is_some_and(|inner| inner.is_empty())
+    {
+        table.remove(name);
+    }
+    removed
+}
+
+fn unset(project: bool, key: &str) {
+    let (path, _) = parse_key(key);
+
+    edit(project, |document, file| {
+        if !remove(document.as_table_mut(), &path) {
+            panic!("{} is not set in {}.", key, file.display());
+        }
+    });
+}
+
+pub fn command(command: ConfigCommand) {
+    match command {
+        ConfigCommand::Get { key } => get(&key),
+        ConfigCommand::Set {
+            project,
+            key,
+            values,
+        } => set(project, &key, &values),
+        ConfigCommand::Unset { project, key } => unset(project, &key),
+        ConfigCommand::List => list(),
+    }
+}
@@ -0,0 +1,187 @@
+// qemu's gdb stub, --gdb, and emu debug which attaches a debugger to it.
+// qemu-user stops the guest before its first instruction and waits until a
+// debugger connects.
+
+use log::info;
+
+use std::fs;
+use std::net::TcpListener;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::cli::Debugger;
+use crate::foreign;
+use crate::shell;
+
+pub const DEFAULT_PORT: &str = "1234";
+
+// What gdb needs to attach: the sysroot for the loader and libraries, the
+// executable for its symbols and the stub
+pub fn commands(port: u16, program: &Path, sysroot: &str) -> Vec<String> {
+    let program = fs::canonicalize(program).unwrap_or_else(|_| program.to_path_buf());
+    let mut commands = Vec::new();
+    if !sysroot.is_empty() {
+        commands.push(format!("set sysroot {}", sysroot));
+    }
+    commands.push(format!("file {}", program.display()));
+    commands.push(format!("target remote :{}", port));
+    commands
+}
+
+fn lldb_commands(port: u16, program: &Path, sysroot: &str) -> Vec<String> {
+    let program = fs::canonicalize(program).unwrap_or_else(|_| program.to_path_buf());
+    let mut commands = Vec::new();
+    if !sysroot.is_empty() {
+        commands.push(format!(
+            "platform select remote-linux --sysroot {}",
+            sysroot
+        ));
+    }
+    commands.push(format!("target create {}", program.display()));
+    commands.push(format!("gdb-remote {}", port));
+    commands
+}
+
+// The same commands as one line to paste into a shell
+pub fn command_line(commands: &[String]) -> String {
+    let mut words = vec![String::from("gdb-multiarch")];
+    for command in commands {
+        words.push(String::from("-ex"));
+        words.push(shell::quote(command.as_ref()));
+    }
+    words.join(" ")
+}
+
+pub fn print_connect(port: u16, program: &Path, sysroot: &str) {
+    let commands = commands(port, program, sysroot);
+    eprintln!("Waiting for gdb on port {}, connect with", port);
+    eprintln!();
+    eprintln!("  {}", command_line(&commands));
+    eprintln!();
+    eprintln!("or from a running gdb-multiarch:");
+    eprintln!();
+    for command in &commands {
+        eprintln!("  {}", command);
+    }
+    eprintln!();
+}
+
+// A port nothing listens on for emu debug, so several sessions can run
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|address| address.port())
+        .unwrap_or_else(|e| panic!("Unable to find a free port for the gdb stub: {}", e))
+}
+
+// The debugger started preconfigured for the stub, gdb-multiarch unless only
+// a native gdb is installed
+pub fn debugger_command(debugger: Debugger, port: u16, program: &Path, sysroot: &str) -> Command {
+    match debugger {
+        Debugger::Gdb => {
+            let gdb = if foreign::in_path("gdb-multiarch") || !foreign::in_path("gdb") {
+                "gdb-multiarch"
+            } else {
+                "gdb"
+            };
+            let mut command = Command::new(gdb);
+            command.arg("-q");
+            for line in commands(port, program, sysroot) {
+                command.arg("-ex").arg(line);
+            }
+            command
+        }
+        Debugger::Lldb => {
+            let mut command = Command::new("lldb");
+            for line in lldb_commands(port, program, sysroot) {
+                command.arg("-o").arg(line);
+            }
+            command
+        }
+    }
+}
+
+// Whether something listens on port, read from /proc/net since connecting
+// would use up the stub's only connection
+fn listening(port: u16) -> bool {
+    let port = format!(":{:04X}", port);
+    ["/proc/net/tcp", "/proc/net/tcp6"].iter().any(|path| {
+        fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .skip(1)
+            .any(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                // st 0A is LISTEN
+                fields.len() > 3 && fields[1].ends_with(&port) && fields[3] == "0A"
+            })
+    })
+}
+
+fn wait_for_stub(qemu: &mut Child, port: u16) {
+    let start = Instant::now();
+    while !listening(port) {
+        if let Ok(Some(status)) = qemu.try_wait() {
+            panic!("qemu exited ({}) before the debugger could attach.", status);
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            let _ = qemu.kill();
+            panic!("qemu didn't open its gdb stub on port {}.", port);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// signal(2) from the C library, sighandler_t is a pointer sized integer
+extern "C" {
+    fn signal(signum: i32, handler: usize) -> usize;
+}
+
+// Like system(3), Ctrl-C and Ctrl-\ are for the debugger while it runs
+fn ignore_interrupts() {
+    const SIGINT: i32 = 2;
+    const SIGQUIT: i32 = 3;
+    const SIG_IGN: usize = 1;
+    // SAFETY: SIGINT and SIGQUIT have these numbers on every Linux
+    // architecture, SIG_IGN is a valid handler for them and emu installs no
+    // handlers of its own that this could race with.
+    unsafe {
+        signal(SIGINT, SIG_IGN);
+        signal(SIGQUIT, SIG_IGN);
+    }
+}
+
+// Runs qemu in its own process group, so only the debugger sees the
+// terminal's signals, and the debugger in the foreground. A background
+// process reading the terminal is stopped with SIGTTIN, so the guest's stdin
+// is /dev/null. qemu is killed when the debugger exits, the debugger's status
+// is returned.
+pub fn run(mut qemu: Command, mut debugger: Command, port: u16) -> ExitStatus {
+    let name = debugger.get_program().to_string_lossy().to_string();
+    if !foreign::in_path(&name) {
+        panic!("{} is not installed, it is needed for emu debug.", name);
+    }
+
+    qemu.process_group(0).stdin(Stdio::null());
+    let mut qemu = qemu.spawn().unwrap_or_else(|e| {
+        panic!(
+            "Unable to run {} ({}).",
+            qemu.get_program().to_string_lossy(),
+            e
+        )
+    });
+    wait_for_stub(&mut qemu, port);
+
+    info!("running {}", shell::command_line(&debugger));
+    let status = debugger.spawn().and_then(|mut debugger| {
+        ignore_interrupts();
+        debugger.wait()
+    });
+
+    let _ = qemu.kill();
+    let _ = qemu.wait();
+    status.unwrap_or_else(|e| panic!("Unable to run {} ({}).", name, e))
+}
@@ -0,0 +1,187 @@
+// Sysroots that are already on the machine because an SDK or a toolchain put
+// them there.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::sysroot;
+
+fn subdirs(dir: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.is_dir())
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+fn environment_setups(sdk: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(sdk)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("environment-setup-"))
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+// A Yocto SDK has an environment-setup-<tuple> script per target next to
+// sysroots/, e.g. /opt/poky/4.0/environment-setup-cortexa57-poky-linux.
+fn is_yocto_sdk(dir: &Path) -> bool {
+    !environment_setups(dir).is_empty() || dir.join("sysroots").is_dir()
+}
+
+// Yocto SDKs installed in their default places, /opt/<distro>/<version>
+fn yocto_sdks() -> Vec<PathBuf> {
+    let mut sdks = Vec::new();
+    for dir in subdirs(Path::new("/opt")) {
+        if is_yocto_sdk(&dir) {
+            sdks.push(dir);
+            continue;
+        }
+        sdks.extend(subdirs(&dir).into_iter().filter(|dir| is_yocto_sdk(dir)));
+    }
+    sdks
+}
+
+// The target sysroots of an SDK, from SDKTARGETSYSROOT in the
+// environment-setup scripts or else sysroots/<tuple>. The SDK's own host
+// tools are in sysroots/<host>-<distro>sdk-linux.
+fn yocto_sysroots(sdk: &Path) -> Vec<PathBuf> {
+    let mut sysroots: Vec<PathBuf> = environment_setups(sdk)
+        .iter()
+        .filter_map(|file| fs::read_to_string(file).ok())
+        .filter_map(|script| {
+            script.lines().find_map(|line| {
+                let line = line.trim().trim_start_matches("export ");
+                let value = line.strip_prefix("SDKTARGETSYSROOT=")?;
+                Some(PathBuf::from(value.trim_matches(['"', '\''])))
+            })
+        })
+        .filter(|sysroot| sysroot.is_dir())
+        .collect();
+    if sysroots.is_empty() {
+        sysroots = subdirs(&sdk.join("sysroots"))
+            .into_iter()
+            .filter(|dir| {
+                !dir.file_name()
+                    .is_some_and(|name| name.to_string_lossy().ends_with("sdk-linux"))
+            })
+            .collect();
+    }
+    sysroots
+}
+
+// A Buildroot output directory (output/ or the O= directory) has target/, the
+// root filesystem, next to host/ with the toolchain and build/.
+fn is_buildroot_output(dir: &Path) -> bool {
+    dir.join("target").is_dir()
+        && dir.join("host").is_dir()
+        && (dir.join("build").is_dir() || dir.join("staging").exists())
+}
+
+// The Buildroot target directory an executable was built into, so
+// emu output/target/usr/bin/foo needs no sysroot
+pub fn buildroot_target(program: &Path) -> Option<PathBuf> {
+    let program = fs::canonicalize(program).ok()?;
+    program
+        .ancestors()
+        .filter(|dir| dir.file_name().is_some_and(|name| name == "target"))
+        .filter_map(Path::parent)
+        .find(|output| is_buildroot_output(output))
+        .map(|output| output.join("target"))
+}
+
+// The loader from the toolchain when the sysroot is a Buildroot target
+// directory without one, staging/ is the toolchain's sysroot.
+pub fn toolchain_loader(sysroot: &Path, interpreter: &str) -> Option<PathBuf> {
+    let output = sysroot.parent()?;
+    if sysroot.file_name()? != "target" || !is_buildroot_output(output) {
+        return None;
+    }
+    crate::resolve_in_sysroot(&output.join("staging"), Path::new(interpreter))
+}
+
+// crosstool-NG installs toolchains into $CT_PREFIX (~/x-tools by default) as
+// <tuple>/, with the libc it built in <tuple>/<tuple>/sysroot.
+fn crosstool_ng_sysroot(toolchain: &Path) -> Option<PathBuf> {
+    let tuple = toolchain.file_name()?;
+    let sysroot = toolchain.join(tuple).join("sysroot");
+    sysroot.is_dir().then_some(sysroot)
+}
+
+fn crosstool_ng_sysroots() -> Vec<PathBuf> {
+    let mut prefixes: Vec<PathBuf> = env::var_os("CT_PREFIX")
+        .filter(|prefix| !prefix.is_empty())
+        .map(PathBuf::from)
+        .into_iter()
+        .collect();
+    if let Some(home) = env::var_os("HOME") {
+        prefixes.push(PathBuf::from(home).join("x-tools"));
+    }
+    prefixes
+        .iter()
+        .flat_map(|prefix| subdirs(prefix))
+        .filter_map(|toolchain| crosstool_ng_sysroot(&toolchain))
+        .collect()
+}
+
+fn matches(sysroot: &Path, qemu: Option<&str>) -> bool {
+    match sysroot::detect(sysroot) {
+        Some((arch, _)) => qemu.is_none_or(|qemu| qemu == arch),
+        None => false,
+    }
+}
+
+// A directory that isn't a sysroot itself but holds them, such as an SDK or
+// a toolchain, stands for its sysroot for the architecture, or its only one.
+pub fn expand(path: &Path, qemu: Option<&str>) -> Option<PathBuf> {
+    // A Buildroot tree or its output directory
+    for output in [path.join("output"), path.to_path_buf()] {
+        if is_buildroot_output(&output) {
+            return Some(output.join("target"));
+        }
+    }
+
+    if let Some(sysroot) = crosstool_ng_sysroot(path) {
+        return Some(sysroot);
+    }
+
+    if !is_yocto_sdk(path) {
+        return None;
+    }
+    let sysroots: Vec<PathBuf> = yocto_sysroots(path)
+        .into_iter()
+        .filter(|sysroot| matches(sysroot, qemu))
+        .collect();
+    match sysroots.as_slice() {
+        [sysroot] => Some(sysroot.clone()),
+        [sysroot, ..] if qemu.is_some() => Some(sysroot.clone()),
+        _ => None,
+    }
+}
+
+// Every sysroot found on the machine for qemu, in priority order
+pub fn sysroots(qemu: &str) -> Vec<String> {
+    let mut sysroots = Vec::new();
+    for sdk in yocto_sdks() {
+        sysroots.extend(yocto_sysroots(&sdk));
+    }
+    sysroots.extend(crosstool_ng_sysroots());
+
+    let mut found: Vec<String> = Vec::new();
+    for sysroot in sysroots {
+        let sysroot = sysroot.to_string_lossy().to_string();
+        if !found.contains(&sysroot) && matches(Path::new(&sysroot), Some(qemu)) {
+            found.push(sysroot);
+        }
+    }
+    found
+}
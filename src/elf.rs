@@ -0,0 +1,690 @@
+use num_enum::TryFromPrimitive;
+use std::convert::TryFrom;
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+static ELF_MAGIC: [u8; 4] = [0x7f, 0x45, 0x4c, 0x46];
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum ELFClass {
+    ELFCLASS32 = 1,
+    ELFCLASS64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum Endian {
+    Little = 1,
+    Big,
+}
+
+pub enum Attribute {
+    Int(u64),
+    Str(String),
+}
+
+const TAG_ARM_COMPATIBILITY: u64 = 32;
+
+const NT_GNU_ABI_TAG: u32 = 1;
+pub const NT_GNU_PROPERTY_TYPE_0: u32 = 5;
+// NT_GNU_ABI_TAG operating system
+const ELF_NOTE_OS_LINUX: u32 = 0;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_NOTE: u32 = 4;
+
+pub const ET_REL: u16 = 1;
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+pub const ET_CORE: u16 = 4;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_STRSZ: u64 = 10;
+const DT_SONAME: u64 = 14;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+const DT_FLAGS_1: u64 = 0x6ffffffb;
+
+const DF_1_PIE: u64 = 0x08000000;
+
+struct ProgramHeader {
+    p_type: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+}
+
+struct SectionTable {
+    offset: u64,
+    entry_size: u16,
+    count: u16,
+    names_index: u16,
+}
+
+#[macro_export]
+macro_rules! unpack {
+    ($bytes:expr, $inttype:ty, $endian:expr) => {
+        match $endian {
+            $crate::elf::Endian::Little => <$inttype>::from_le_bytes($bytes),
+            $crate::elf::Endian::Big => <$inttype>::from_be_bytes($bytes),
+        }
+    };
+}
+
+fn read_uleb128(data: &[u8], pos: &mut usize) -> u64 {
+    let mut result: u64 = 0;
+    let mut shift = 0;
+
+    while *pos < data.len() {
+        let byte = data[*pos];
+        *pos += 1;
+
+        if shift < 64 {
+            result |= ((byte & 0x7f) as u64) << shift;
+        }
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+
+    result
+}
+
+fn read_ntbs(data: &[u8], pos: &mut usize) -> String {
+    let start = *pos;
+    while *pos < data.len() && data[*pos] != 0 {
+        *pos += 1;
+    }
+    let value = String::from_utf8_lossy(&data[start..*pos]).to_string();
+    // Skip the terminator
+    *pos += 1;
+
+    value
+}
+
+// Parse a build attributes section (.ARM.attributes, .riscv.attributes) and
+// return the file scope attributes of the given vendor. Tags below 32 listed
+// in string_tags are strings, for the rest odd tags are strings and even tags
+// are integers.
+fn parse_build_attributes(
+    data: &[u8],
+    endian: &Endian,
+    vendor: &str,
+    string_tags: &[u64],
+) -> Vec<(u64, Attribute)> {
+    const TAG_FILE: u8 = 1;
+
+    let mut attributes = Vec::new();
+
+    // Format version 'A'
+    if data.first() != Some(&b'A') {
+        return attributes;
+    }
+
+    let mut pos = 1;
+    while pos + 4 <= data.len() {
+        let length = unpack!(data[pos..pos + 4].try_into().unwrap(), u32, endian) as usize;
+        if length < 4 || length > data.len() - pos {
+            break;
+        }
+        let subsection = &data[pos + 4..pos + length];
+        pos += length;
+
+        let mut sub_pos = 0;
+        if read_ntbs(subsection, &mut sub_pos) != vendor {
+            continue;
+        }
+
+        while sub_pos + 5 <= subsection.len() {
+            let tag = subsection[sub_pos];
+            let size = unpack!(
+                subsection[sub_pos + 1..sub_pos + 5].try_into().unwrap(),
+                u32,
+                endian
+            ) as usize;
+            if size < 5 || size > subsection.len() - sub_pos {
+                break;
+            }
+            let contents = &subsection[sub_pos + 5..sub_pos + size];
+            sub_pos += size;
+
+            if tag != TAG_FILE {
+                continue;
+            }
+
+            let mut attr_pos = 0;
+            while attr_pos < contents.len() {
+                let attr_tag = read_uleb128(contents, &mut attr_pos);
+                if attr_tag == TAG_ARM_COMPATIBILITY && vendor == "aeabi" {
+                    // Flag followed by a vendor name
+                    read_uleb128(contents, &mut attr_pos);
+                    read_ntbs(contents, &mut attr_pos);
+                } else if string_tags.contains(&attr_tag) || (attr_tag >= 32 && attr_tag % 2 == 1) {
+                    let value = read_ntbs(contents, &mut attr_pos);
+                    attributes.push((attr_tag, Attribute::Str(value)));
+                } else {
+                    let value = read_uleb128(contents, &mut attr_pos);
+                    attributes.push((attr_tag, Attribute::Int(value)));
+                }
+            }
+        }
+    }
+
+    attributes
+}
+
+/*
+typedef struct {
+    uint32_t   p_type;
+    Elf32_Off  p_offset;
+    Elf32_Addr p_vaddr;
+    Elf32_Addr p_paddr;
+    uint32_t   p_filesz;
+    uint32_t   p_memsz;
+    uint32_t   p_flags;
+    uint32_t   p_align;
+} Elf32_Phdr;
+
+typedef struct {
+    uint32_t   p_type;
+    uint32_t   p_flags;
+    Elf64_Off  p_offset;
+    Elf64_Addr p_vaddr;
+    Elf64_Addr p_paddr;
+    uint64_t   p_filesz;
+    uint64_t   p_memsz;
+    uint64_t   p_align;
+} Elf64_Phdr;
+*/
+fn read_program_headers(
+    f: &mut File,
+    class: &ELFClass,
+    endian: &Endian,
+    offset: u64,
+    entry_size: u16,
+    count: u16,
+) -> Result<Vec<ProgramHeader>, io::Error> {
+    let mut headers = Vec::with_capacity(count as usize);
+    let length = f.metadata()?.len();
+
+    for index in 0..count as u64 {
+        // Offsets come from the file, which may be anything
+        let position = index
+            .checked_mul(entry_size as u64)
+            .and_then(|position| position.checked_add(offset))
+            .filter(|position| *position < length)
+            .ok_or_else(|| invalid("has program headers past the end of the file"))?;
+        f.seek(SeekFrom::Start(position))?;
+
+        let mut p_type = [0; 4];
+        f.read_exact(&mut p_type)?;
+        let p_type: u32 = unpack!(p_type, u32, endian);
+
+        let header = match class {
+            ELFClass::ELFCLASS32 => {
+                let mut fields = [0; 4 * 4];
+                f.read_exact(&mut fields)?;
+                let field = |index: usize| -> u64 {
+                    unpack!(
+                        fields[index * 4..index * 4 + 4].try_into().unwrap(),
+                        u32,
+                        endian
+                    )
+                    .into()
+                };
+                // p_offset, p_vaddr, p_paddr, p_filesz
+                ProgramHeader {
+                    p_type,
+                    offset: field(0),
+                    vaddr: field(1),
+                    filesz: field(3),
+                }
+            }
+            ELFClass::ELFCLASS64 => {
+                // skip p_flags
+                f.seek(SeekFrom::Current(4))?;
+                let mut fields = [0; 8 * 4];
+                f.read_exact(&mut fields)?;
+                let field = |index: usize| -> u64 {
+                    unpack!(
+                        fields[index * 8..index * 8 + 8].try_into().unwrap(),
+                        u64,
+                        endian
+                    )
+                };
+                // p_offset, p_vaddr, p_paddr, p_filesz
+                ProgramHeader {
+                    p_type,
+                    offset: field(0),
+                    vaddr: field(1),
+                    filesz: field(3),
+                }
+            }
+        };
+        headers.push(header);
+    }
+
+    Ok(headers)
+}
+
+// Map a virtual address to its file offset through the PT_LOAD segments.
+fn vaddr_to_offset(headers: &[ProgramHeader], vaddr: u64) -> Option<u64> {
+    headers
+        .iter()
+        .find(|header| {
+            header.p_type == PT_LOAD
+                && vaddr >= header.vaddr
+                && vaddr - header.vaddr < header.filesz
+        })
+        .and_then(|header| (vaddr - header.vaddr).checked_add(header.offset))
+}
+
+type DynamicEntries = (Vec<(u64, u64)>, Vec<(u64, String)>);
+
+// Returns the entries of the dynamic section and its string valued entries
+// (DT_NEEDED, DT_SONAME, DT_RPATH, DT_RUNPATH) in file order.
+fn read_dynamic(
+    f: &mut File,
+    class: &ELFClass,
+    endian: &Endian,
+    headers: &[ProgramHeader],
+) -> Result<DynamicEntries, io::Error> {
+    let dynamic = match headers.iter().find(|header| header.p_type == PT_DYNAMIC) {
+        Some(dynamic) => dynamic,
+        None => return Ok((Vec::new(), Vec::new())),
+    };
+
+    let data = read_at(f, dynamic.offset, dynamic.filesz)?;
+    let mut entries = Vec::new();
+    let entry_size = match class {
+        ELFClass::ELFCLASS32 => 8,
+        ELFClass::ELFCLASS64 => 16,
+    };
+    for entry in data.chunks_exact(entry_size) {
+        let (tag, value): (u64, u64) = match class {
+            ELFClass::ELFCLASS32 => (
+                unpack!(entry[..4].try_into().unwrap(), u32, endian).into(),
+                unpack!(entry[4..].try_into().unwrap(), u32, endian).into(),
+            ),
+            ELFClass::ELFCLASS64 => (
+                unpack!(entry[..8].try_into().unwrap(), u64, endian),
+                unpack!(entry[8..].try_into().unwrap(), u64, endian),
+            ),
+        };
+        if tag == DT_NULL {
+            break;
+        }
+        entries.push((tag, value));
+    }
+
+    let strtab = entries.iter().find(|(tag, _)| *tag == DT_STRTAB);
+    let strsz = entries.iter().find(|(tag, _)| *tag == DT_STRSZ);
+    let strings = match (strtab, strsz) {
+        (Some((_, address)), Some((_, size))) => match vaddr_to_offset(headers, *address) {
+            Some(offset) => read_at(f, offset, *size)?,
+            None => return Ok((entries, Vec::new())),
+        },
+        _ => return Ok((entries, Vec::new())),
+    };
+
+    let string_entries = entries
+        .iter()
+        .filter(|(tag, _)| [DT_NEEDED, DT_SONAME, DT_RPATH, DT_RUNPATH].contains(tag))
+        .filter(|(_, value)| (*value as usize) < strings.len())
+        .map(|(tag, value)| {
+            let mut pos = *value as usize;
+            (*tag, read_ntbs(&strings, &mut pos))
+        })
+        .collect();
+
+    Ok((entries, string_entries))
+}
+
+// Returns the sh_name, sh_offset and sh_size fields of a section header.
+fn read_section_header(
+    f: &mut File,
+    class: &ELFClass,
+    endian: &Endian,
+    sections: &SectionTable,
+    index: u16,
+) -> Result<(u32, u64, u64), io::Error> {
+    let position = (index as u64)
+        .checked_mul(sections.entry_size as u64)
+        .and_then(|position| position.checked_add(sections.offset))
+        .filter(|position| *position < f.metadata().map_or(0, |metadata| metadata.len()))
+        .ok_or_else(|| invalid("has section headers past the end of the file"))?;
+    f.seek(SeekFrom::Start(position))?;
+
+    let mut sh_name = [0; 4];
+    f.read_exact(&mut sh_name)?;
+    let name: u32 = unpack!(sh_name, u32, endian);
+
+    match class {
+        ELFClass::ELFCLASS32 => {
+            let mut sh_offset = [0; 4];
+            let mut sh_size = [0; 4];
+            // Skip sh_type + sh_flags + sh_addr
+            f.seek(SeekFrom::Current(4 + 4 + 4))?;
+            f.read_exact(&mut sh_offset)?;
+            f.read_exact(&mut sh_size)?;
+            Ok((
+                name,
+                unpack!(sh_offset, u32, endian).into(),
+                unpack!(sh_size, u32, endian).into(),
+            ))
+        }
+        ELFClass::ELFCLASS64 => {
+            let mut sh_offset = [0; 8];
+            let mut sh_size = [0; 8];
+            // Skip sh_type + sh_flags + sh_addr
+            f.seek(SeekFrom::Current(4 + 8 + 8))?;
+            f.read_exact(&mut sh_offset)?;
+            f.read_exact(&mut sh_size)?;
+            Ok((
+                name,
+                unpack!(sh_offset, u64, endian),
+                unpack!(sh_size, u64, endian),
+            ))
+        }
+    }
+}
+
+// Sizes come from the file too, nothing past its end is read
+fn read_at(f: &mut File, offset: u64, size: u64) -> Result<Vec<u8>, io::Error> {
+    let size = size.min(f.metadata()?.len().saturating_sub(offset));
+    f.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    f.take(size).read_to_end(&mut data)?;
+
+    Ok(data)
+}
+
+// Returns the contents of the named section, stripped binaries might not
+// have a section table at all.
+fn read_section(
+    f: &mut File,
+    class: &ELFClass,
+    endian: &Endian,
+    sections: &SectionTable,
+    name: &str,
+) -> Result<Option<Vec<u8>>, io::Error> {
+    if sections.offset == 0 || sections.names_index >= sections.count {
+        return Ok(None);
+    }
+
+    let (_, names_offset, names_size) =
+        read_section_header(f, class, endian, sections, sections.names_index)?;
+    let names = read_at(f, names_offset, names_size)?;
+
+    for index in 0..sections.count {
+        let (name_offset, offset, size) = read_section_header(f, class, endian, sections, index)?;
+        let mut pos = name_offset as usize;
+        if pos < names.len() && read_ntbs(&names, &mut pos) == name {
+            return Ok(Some(read_at(f, offset, size)?));
+        }
+    }
+
+    Ok(None)
+}
+
+// Walk the notes in a SHT_NOTE section and return the descriptors of the
+// notes with the given owner and type.
+pub fn parse_notes(data: &[u8], endian: &Endian, owner: &[u8], note_type: u32) -> Vec<Vec<u8>> {
+    let align4 = |value: usize| value.checked_add(3).map(|value| value & !3);
+    let mut descriptors = Vec::new();
+
+    let mut pos = 0;
+    while pos + 12 <= data.len() {
+        let namesz = unpack!(data[pos..pos + 4].try_into().unwrap(), u32, endian) as usize;
+        let descsz = unpack!(data[pos + 4..pos + 8].try_into().unwrap(), u32, endian) as usize;
+        let n_type: u32 = unpack!(data[pos + 8..pos + 12].try_into().unwrap(), u32, endian);
+
+        let name_start = pos + 12;
+        let desc_start = match align4(namesz).and_then(|size| name_start.checked_add(size)) {
+            Some(desc_start) if desc_start <= data.len() && descsz <= data.len() - desc_start => {
+                desc_start
+            }
+            _ => break,
+        };
+
+        if n_type == note_type && &data[name_start..name_start + namesz] == owner {
+            descriptors.push(data[desc_start..desc_start + descsz].to_vec());
+        }
+
+        pos = match align4(descsz).and_then(|size| desc_start.checked_add(size)) {
+            Some(pos) => pos,
+            None => break,
+        };
+    }
+
+    descriptors
+}
+
+pub struct ElfInfo {
+    pub class: ELFClass,
+    pub endian: Endian,
+    // EI_OSABI
+    pub osabi: u8,
+    // e_type
+    pub elf_type: u16,
+    pub machine: u16,
+    pub flags: u32,
+    // PT_INTERP, empty for statically linked executables
+    pub interpreter: String,
+    // DT_NEEDED libraries
+    pub needed: Vec<String>,
+    // DT_RPATH and DT_RUNPATH, colon separated lists
+    pub rpath: Vec<String>,
+    pub runpath: Vec<String>,
+    // DF_1_PIE in DT_FLAGS_1
+    pie: bool,
+    // Lowest PT_LOAD address
+    pub load_address: u64,
+    // Minimum kernel version from NT_GNU_ABI_TAG
+    pub min_kernel: Option<(u32, u32, u32)>,
+    sections: SectionTable,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl ElfInfo {
+    // https://man7.org/linux/man-pages/man5/elf.5.html
+    //  #define EI_NIDENT 16
+
+    // typedef struct {
+    //      unsigned char e_ident[EI_NIDENT];
+    //      uint16_t      e_type;
+    //      uint16_t      e_machine;
+    //      uint32_t      e_version;
+    //      ElfN_Addr     e_entry; (uint32_t or uint64_t)
+    //      ElfN_Off      e_phoff; (uint32_t or uint64_t)
+    //      ElfN_Off      e_shoff; (uint32_t or uint64_t)
+    //      uint32_t      e_flags;
+    //      uint16_t      e_ehsize;
+    //      uint16_t      e_phentsize;
+    //      uint16_t      e_phnum;
+    //      uint16_t      e_shentsize;
+    //      uint16_t      e_shnum;
+    //      uint16_t      e_shstrndx;
+    // } ElfN_Ehdr;
+    pub fn parse(f: &mut File) -> Result<ElfInfo, io::Error> {
+        f.seek(SeekFrom::Start(0))?;
+        // Elf64_Ehdr is the larger of the two
+        let mut header = Vec::with_capacity(64);
+        f.take(64).read_to_end(&mut header)?;
+
+        if header.len() < 16 || header[..4] != ELF_MAGIC {
+            return Err(invalid("is not an ELF file"));
+        }
+
+        // EI_CLASS
+        let class =
+            ELFClass::try_from(header[4]).map_err(|_| invalid("has an invalid ELF class"))?;
+
+        // EI_DATA
+        let endian =
+            Endian::try_from(header[5]).map_err(|_| invalid("has an unknown endianness"))?;
+
+        let header_size = match class {
+            ELFClass::ELFCLASS32 => 52,
+            ELFClass::ELFCLASS64 => 64,
+        };
+        if header.len() < header_size {
+            return Err(invalid("has a truncated ELF header"));
+        }
+
+        let half = |offset: usize| -> u16 {
+            unpack!(header[offset..offset + 2].try_into().unwrap(), u16, &endian)
+        };
+        let word = |offset: usize| -> u32 {
+            unpack!(header[offset..offset + 4].try_into().unwrap(), u32, &endian)
+        };
+        // ElfN_Addr and ElfN_Off
+        let address = |offset: usize| -> u64 {
+            match class {
+                ELFClass::ELFCLASS32 => word(offset).into(),
+                ELFClass::ELFCLASS64 => {
+                    unpack!(header[offset..offset + 8].try_into().unwrap(), u64, &endian)
+                }
+            }
+        };
+
+        // Everything after e_entry moves by 4 bytes for 64-bit
+        let wide = match class {
+            ELFClass::ELFCLASS32 => 0,
+            ELFClass::ELFCLASS64 => 4,
+        };
+        let pheader_offset = address(28 + wide);
+        let sections = SectionTable {
+            offset: address(32 + wide * 2),
+            entry_size: half(46 + wide * 3),
+            count: half(48 + wide * 3),
+            names_index: half(50 + wide * 3),
+        };
+
+        let mut info = ElfInfo {
+            class,
+            endian,
+            osabi: header[7],
+            elf_type: half(16),
+            machine: half(18),
+            flags: word(36 + wide * 3),
+            interpreter: String::new(),
+            needed: Vec::new(),
+            rpath: Vec::new(),
+            runpath: Vec::new(),
+            pie: false,
+            load_address: 0,
+            min_kernel: None,
+            sections,
+        };
+
+        let program_headers = read_program_headers(
+            f,
+            &class,
+            &endian,
+            pheader_offset,
+            half(42 + wide * 3),
+            half(44 + wide * 3),
+        )?;
+
+        info.load_address = program_headers
+            .iter()
+            .filter(|header| header.p_type == PT_LOAD)
+            .map(|header| header.vaddr)
+            .min()
+            .unwrap_or(0);
+
+        if let Some(interp) = program_headers
+            .iter()
+            .find(|header| header.p_type == PT_INTERP)
+        {
+            // PATH_MAX
+            if interp.filesz > 4096 {
+                return Err(invalid("has an interpreter path longer than PATH_MAX"));
+            }
+            let interpreter = read_at(f, interp.offset, interp.filesz)?;
+            // interpreter is null terminated
+            info.interpreter = String::from_utf8_lossy(&interpreter)
+                .trim_end_matches('\0')
+                .to_string();
+        }
+
+        let (dynamic, strings) = read_dynamic(f, &class, &endian, &program_headers)?;
+        info.pie = dynamic
+            .iter()
+            .any(|(tag, value)| *tag == DT_FLAGS_1 && value & DF_1_PIE != 0);
+        let entries_of = |wanted: u64| -> Vec<String> {
+            strings
+                .iter()
+                .filter(|(tag, _)| *tag == wanted)
+                .map(|(_, value)| value.clone())
+                .collect()
+        };
+        info.needed = entries_of(DT_NEEDED);
+        info.rpath = entries_of(DT_RPATH);
+        info.runpath = entries_of(DT_RUNPATH);
+
+        // Read from PT_NOTE so stripped executables without a section table
+        // still have it.
+        for note in program_headers
+            .iter()
+            .filter(|header| header.p_type == PT_NOTE)
+        {
+            let data = read_at(f, note.offset, note.filesz)?;
+            for desc in parse_notes(&data, &endian, b"GNU\0", NT_GNU_ABI_TAG) {
+                if desc.len() < 16 {
+                    continue;
+                }
+                let word = |index: usize| -> u32 {
+                    unpack!(
+                        desc[index * 4..index * 4 + 4].try_into().unwrap(),
+                        u32,
+                        &endian
+                    )
+                };
+                if word(0) == ELF_NOTE_OS_LINUX {
+                    info.min_kernel = Some((word(1), word(2), word(3)));
+                }
+            }
+        }
+
+        Ok(info)
+    }
+
+    // ET_DYN is used for both PIE executables and shared libraries, older
+    // linkers don't set DF_1_PIE but PIEs always have an interpreter unless
+    // they are static-pie.
+    pub fn is_shared_library(&self) -> bool {
+        self.elf_type == ET_DYN && !self.pie && self.interpreter.is_empty()
+    }
+
+    pub fn section(&self, f: &mut File, name: &str) -> Result<Option<Vec<u8>>, io::Error> {
+        read_section(f, &self.class, &self.endian, &self.sections, name)
+    }
+
+    pub fn build_attributes(
+        &self,
+        f: &mut File,
+        name: &str,
+        vendor: &str,
+        string_tags: &[u64],
+    ) -> Result<Vec<(u64, Attribute)>, io::Error> {
+        Ok(match self.section(f, name)? {
+            Some(data) => parse_build_attributes(&data, &self.endian, vendor, string_tags),
+            None => Vec::new(),
+        })
+    }
+}
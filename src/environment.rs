@@ -0,0 +1,79 @@
+// The guest's environment. qemu-user hands its own environment to the guest,
+// -E and -U change it on the way. --clean-env removes the host variables
+// instead, except for a few every program expects.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::process::Command;
+
+use crate::cli::RunArgs;
+use crate::config::Settings;
+
+// qemu reads QEMU_* itself, so those stay as well
+const KEPT: &[&str] = &[
+    "HOME", "LOGNAME", "PATH", "SHELL", "TERM", "TMPDIR", "TZ", "USER",
+];
+
+// NAME=VALUE, a bare NAME takes the host's value like docker --env-file.
+// Blank lines and # comments are skipped.
+fn read_env_file(path: &str, env: &mut BTreeMap<String, String>) {
+    let contents =
+        fs::read_to_string(path).unwrap_or_else(|e| panic!("Unable to read {}: {}", path, e));
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        match line.split_once('=') {
+            Some((name, value)) if !name.is_empty() => {
+                env.insert(name.to_string(), value.to_string());
+            }
+            Some(_) => panic!("{}:{}: {} is not NAME=VALUE.", path, number + 1, line),
+            None => {
+                if let Ok(value) = env::var(line) {
+                    env.insert(line.to_string(), value);
+                }
+            }
+        }
+    }
+}
+
+// Variables to set with -E, in order the config files, --env-file and --env
+pub fn set(options: &RunArgs, settings: &Settings) -> BTreeMap<String, String> {
+    let mut env = settings.env.clone();
+    for path in &options.env_files {
+        read_env_file(path, &mut env);
+    }
+    for variable in &options.env {
+        match variable.split_once('=') {
+            Some((name, value)) if !name.is_empty() => {
+                env.insert(name.to_string(), value.to_string());
+            }
+            _ => panic!("{} is not NAME=VALUE, check --env.", variable),
+        }
+    }
+    for name in &options.unset_env {
+        env.remove(name);
+    }
+    env
+}
+
+// Remove what the guest shouldn't see from the environment qemu (or proot)
+// is started with
+pub fn clean(command: &mut Command, options: &RunArgs, settings: &Settings) {
+    if !options.clean_env && settings.clean_env != Some(true) {
+        return;
+    }
+    for (name, _) in env::vars_os() {
+        let text = name.to_string_lossy();
+        let kept = KEPT.contains(&&*text)
+            || text.starts_with("QEMU_")
+            || options.keep_env.iter().any(|kept| *kept == text)
+            || settings.keep_env.iter().any(|kept| *kept == text);
+        if !kept {
+            command.env_remove(name);
+        }
+    }
+}
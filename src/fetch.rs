@@ -0,0 +1,538 @@
+// Prebuilt sysroots for emu sysroot fetch and from-image. Downloading and
+// unpacking is left to curl, tar and the container engines, which know about
+// proxies, certificates, registries and every compression format the sources
+// use.
+
+use clap::ValueEnum;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::arch;
+use crate::archive;
+use crate::cli::FetchSource;
+use crate::foreign;
+use crate::report;
+use crate::sysroot;
+use crate::sysroot::Origin;
+
+// What each source calls an architecture, None when it has no sysroot for it
+struct Names {
+    qemu: &'static str,
+    // Debian and Ubuntu
+    debian: Option<&'static str>,
+    alpine: Option<&'static str>,
+    bootlin: Option<&'static str>,
+    // Container image platform, linux/<oci>
+    oci: Option<&'static str>,
+}
+
+#[rustfmt::skip]
+static NAMES: &[Names] = &[
+    Names { qemu: "aarch64", debian: Some("arm64"), alpine: Some("aarch64"), bootlin: Some("aarch64") , oci: Some("arm64") },
+    Names { qemu: "arm", debian: Some("armhf"), alpine: Some("armv7"), bootlin: Some("armv7-eabihf") , oci: Some("arm/v7") },
+    Names { qemu: "i386", debian: Some("i386"), alpine: Some("x86"), bootlin: Some("x86-i686") , oci: Some("386") },
+    Names { qemu: "loongarch64", debian: Some("loong64"), alpine: Some("loongarch64"), bootlin: None , oci: Some("loong64") },
+    Names { qemu: "mips64el", debian: Some("mips64el"), alpine: None, bootlin: None , oci: Some("mips64le") },
+    Names { qemu: "ppc64le", debian: Some("ppc64el"), alpine: Some("ppc64le"), bootlin: Some("powerpc64le-power8") , oci: Some("ppc64le") },
+    Names { qemu: "riscv64", debian: Some("riscv64"), alpine: Some("riscv64"), bootlin: Some("riscv64-lp64d") , oci: Some("riscv64") },
+    Names { qemu: "s390x", debian: Some("s390x"), alpine: Some("s390x"), bootlin: Some("s390x-z13") , oci: Some("s390x") },
+    Names { qemu: "x86_64", debian: Some("amd64"), alpine: Some("x86_64"), bootlin: Some("x86-64") , oci: Some("amd64") },
+];
+
+// Debian's name for a qemu name, e.g. arm64
+pub fn debian_arch(qemu: &str) -> Option<&'static str> {
+    NAMES
+        .iter()
+        .find(|names| names.qemu == qemu)
+        .and_then(|names| names.debian)
+}
+
+// Alpine's name for a qemu name, e.g. armv7
+pub fn alpine_arch(qemu: &str) -> Option<&'static str> {
+    NAMES
+        .iter()
+        .find(|names| names.qemu == qemu)
+        .and_then(|names| names.alpine)
+}
+
+// Directory of an Alpine release on the mirrors, 3.20 is in v3.20
+pub fn alpine_branch(release: &str) -> String {
+    match release {
+        "latest-stable" | "edge" => release.to_string(),
+        release => format!("v{}", release.trim_start_matches('v')),
+    }
+}
+
+// $XDG_CACHE_HOME/emu, ~/.cache when it isn't set
+pub fn cache_home() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .map(|dir| dir.join("emu"))
+}
+
+// Downloads are kept there to be reused
+pub fn cache_dir() -> PathBuf {
+    cache_home().expect("Neither XDG_CACHE_HOME nor HOME is set, there is nowhere to download to.")
+}
+
+// Where a tool's stderr goes, with --quiet it is only shown when the tool fails
+pub fn stderr() -> Stdio {
+    if report::quiet() {
+        Stdio::piped()
+    } else {
+        Stdio::inherit()
+    }
+}
+
+// What a failed tool printed to a piped stderr, for the error message
+pub fn failure(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    match stderr.trim() {
+        "" => String::new(),
+        stderr => format!("\n{}", stderr),
+    }
+}
+
+// Run a tool emu drives, with its output going to the terminal unless
+// --quiet was given
+pub fn run(mut command: Command) {
+    let program = command.get_program().to_string_lossy().to_string();
+    if report::quiet() {
+        command.stdout(Stdio::null());
+    }
+    let output = command
+        .stderr(stderr())
+        .output()
+        .unwrap_or_else(|e| panic!("Unable to run {}: {}, is it installed?", program, e));
+    if !output.status.success() {
+        panic!(
+            "{} failed ({}).{}",
+            program,
+            output.status,
+            failure(&output.stderr)
+        );
+    }
+}
+
+// Body of a small file, like a directory listing
+pub fn get(url: &str) -> String {
+    let output = Command::new("curl")
+        .args(["-fsSL", url])
+        .output()
+        .unwrap_or_else(|e| panic!("Unable to run curl: {}, is it installed?", e));
+    if !output.status.success() {
+        panic!(
+            "Unable to download {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+// downloads/<host>/<path>, a file name alone isn't unique: every
+// linuxcontainers.org image is a rootfs.tar.xz
+fn cache_path(url: &str) -> PathBuf {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let mut path = cache_dir().join("downloads");
+    for part in rest
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != "." && *part != "..")
+    {
+        path.push(part);
+    }
+    path
+}
+
+// url in the download cache, fetched unless an earlier run already did. With
+// a checksum the cached file has to match it and so does the download.
+pub fn download(url: &str, sha256: Option<&str>) -> PathBuf {
+    let path = cache_path(url);
+    if path.is_file() && sha256.is_none_or(|sha256| archive::sha256(&path) == sha256) {
+        return path;
+    }
+    download_to(url, &path);
+    if let Some(sha256) = sha256 {
+        let downloaded = archive::sha256(&path);
+        if downloaded != sha256 {
+            let _ = fs::remove_file(&path);
+            panic!(
+                "{} doesn't match its published checksum (sha256 {} instead of {}).",
+                url, downloaded, sha256
+            );
+        }
+    }
+    path
+}
+
+// Download url to path, replacing it
+pub fn download_to(url: &str, path: &Path) {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .unwrap_or_else(|e| panic!("Unable to create {}: {}", dir.display(), e));
+    }
+    // Interrupted downloads must not look finished
+    let partial = path.with_file_name(format!(
+        "{}.partial",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let mut curl = Command::new("curl");
+    curl.arg("-fL")
+        .arg("--progress-bar")
+        .arg("-o")
+        .arg(&partial)
+        .arg(url);
+    run(curl);
+    fs::rename(&partial, path)
+        .unwrap_or_else(|e| panic!("Unable to rename {}: {}", partial.display(), e));
+}
+
+// Unpack a root filesystem as an unprivileged user, device nodes can't be
+// created and the files end up owned by whoever runs emu.
+pub fn extract(archive: &Path, dir: &Path) {
+    fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Unable to create {}: {}", dir.display(), e));
+    let mut tar = Command::new("tar");
+    tar.arg("-xf").arg(archive).arg("-C").arg(dir).args([
+        "--no-same-owner",
+        "--exclude=./dev/*",
+        "--exclude=dev/*",
+    ]);
+    run(tar);
+}
+
+// href targets in an HTML directory listing
+fn links(html: &str) -> Vec<&str> {
+    html.split("href=\"")
+        .skip(1)
+        .filter_map(|rest| rest.split('"').next())
+        .collect()
+}
+
+// Newest entry of a listing, the sources name them so that they sort
+fn newest<'a>(names: impl Iterator<Item = &'a str>, what: &str, url: &str) -> &'a str {
+    names
+        .max()
+        .unwrap_or_else(|| panic!("There is no {} in {}.", what, url))
+}
+
+// Download URL of the newest build of release
+fn source_url(source: FetchSource, arch: &str, release: &str, libc: &str) -> String {
+    match source {
+        // linuxcontainers.org builds Debian images daily in dated directories
+        FetchSource::Debian => {
+            let base = format!(
+                "https://images.linuxcontainers.org/images/debian/{}/{}/default/",
+                release, arch
+            );
+            let listing = get(&base);
+            let build = newest(
+                links(&listing)
+                    .into_iter()
+                    .filter(|link| link.ends_with('/') && link.starts_with(char::is_numeric)),
+                "build",
+                &base,
+            );
+            format!("{}{}rootfs.tar.xz", base, build)
+        }
+        FetchSource::Ubuntu => {
+            let base = format!(
+                "https://cdimage.ubuntu.com/ubuntu-base/releases/{}/release/",
+                release
+            );
+            let listing = get(&base);
+            let suffix = format!("-base-{}.tar.gz", arch);
+            let file = newest(
+                links(&listing)
+                    .into_iter()
+                    .filter(|link| link.starts_with("ubuntu-base-") && link.ends_with(&suffix)),
+                "ubuntu-base tarball",
+                &base,
+            );
+            format!("{}{}", base, file)
+        }
+        FetchSource::Alpine => {
+            let branch = alpine_branch(release);
+            let base = format!(
+                "https://dl-cdn.alpinelinux.org/alpine/{}/releases/{}/",
+                branch, arch
+            );
+            let index = format!("{}latest-releases.yaml", base);
+            let releases = get(&index);
+            let file = newest(
+                releases
+                    .lines()
+                    .filter_map(|line| line.trim().strip_prefix("file: "))
+                    .filter(|file| file.starts_with("alpine-minirootfs-")),
+                "minirootfs",
+                &index,
+            );
+            format!("{}{}", base, file)
+        }
+        FetchSource::Bootlin => {
+            let base = format!(
+                "https://toolchains.bootlin.com/downloads/releases/toolchains/{}/tarballs/",
+                arch
+            );
+            let listing = get(&base);
+            let prefix = format!("{}--{}--{}-", arch, libc, release);
+            let file = newest(
+                links(&listing)
+                    .into_iter()
+                    .map(|link| link.rsplit('/').next().unwrap_or(link))
+                    .filter(|link| {
+                        link.starts_with(&prefix)
+                            && (link.ends_with(".tar.xz") || link.ends_with(".tar.bz2"))
+                    }),
+                "toolchain",
+                &base,
+            );
+            format!("{}{}", base, file)
+        }
+    }
+}
+
+// The sha256 the source publishes for url, from the SHA256SUMS file of its
+// directory or the <file>.sha256 next to it
+fn published_sha256(source: FetchSource, url: &str) -> String {
+    let (dir, file) = url.rsplit_once('/').unwrap_or(("", url));
+    let sums = match source {
+        FetchSource::Debian | FetchSource::Ubuntu => format!("{}/SHA256SUMS", dir),
+        FetchSource::Alpine | FetchSource::Bootlin => format!("{}.sha256", url),
+    };
+    // "<sha256>  <file>", or " *<file>" for files checksummed in binary mode
+    get(&sums)
+        .lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(_, name)| name.trim_start().trim_start_matches('*') == file)
+        .map(|(sha256, _)| sha256.to_ascii_lowercase())
+        .unwrap_or_else(|| panic!("{} has no checksum for {}.", sums, file))
+}
+
+// A Bootlin toolchain keeps the sysroot in <toolchain>/<tuple>/sysroot
+fn toolchain_sysroot(dir: &Path) -> Option<PathBuf> {
+    let entries = |dir: &Path| -> Vec<PathBuf> {
+        fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.is_dir())
+            .collect()
+    };
+    entries(dir)
+        .iter()
+        .flat_map(|toolchain| entries(toolchain))
+        .map(|tuple| tuple.join("sysroot"))
+        .find(|sysroot| sysroot.is_dir())
+}
+
+// $XDG_DATA_HOME/emu/sysroots/<name> for a new sysroot
+pub fn sysroot_dir(name: &str) -> PathBuf {
+    if sysroot::is_registered(name) {
+        panic!(
+            "There already is a sysroot named {}, pass --name or remove it first.",
+            name
+        );
+    }
+
+    let dir = sysroot::data_dir()
+        .expect("Neither XDG_DATA_HOME nor HOME is set, there is nowhere to keep sysroots.")
+        .join("sysroots")
+        .join(name);
+    if dir.exists() {
+        panic!(
+            "{} already exists, remove it or pass --name.",
+            dir.display()
+        );
+    }
+    dir
+}
+
+// fill gets a directory next to dir which is renamed once it's complete, so
+// a failure leaves nothing behind that looks usable
+pub fn install(dir: &Path, fill: impl FnOnce(&Path)) {
+    let name = dir.file_name().unwrap_or_default().to_string_lossy();
+    let partial = dir.with_file_name(format!("{}.partial", name));
+    let _ = fs::remove_dir_all(&partial);
+    fs::create_dir_all(&partial)
+        .unwrap_or_else(|e| panic!("Unable to create {}: {}", partial.display(), e));
+    fill(&partial);
+    fs::rename(&partial, dir)
+        .unwrap_or_else(|e| panic!("Unable to rename {}: {}", partial.display(), e));
+}
+
+fn source_name(source: FetchSource) -> &'static str {
+    match source {
+        FetchSource::Debian => "debian",
+        FetchSource::Ubuntu => "ubuntu",
+        FetchSource::Alpine => "alpine",
+        FetchSource::Bootlin => "bootlin",
+    }
+}
+
+// What the source calls a qemu architecture
+fn source_arch(source: FetchSource, qemu: &str) -> &'static str {
+    NAMES
+        .iter()
+        .find(|names| names.qemu == qemu)
+        .and_then(|names| match source {
+            FetchSource::Debian | FetchSource::Ubuntu => names.debian,
+            FetchSource::Alpine => names.alpine,
+            FetchSource::Bootlin => names.bootlin,
+        })
+        .unwrap_or_else(|| {
+            panic!(
+                "There are no {} sysroots for {}.",
+                source_name(source),
+                qemu
+            )
+        })
+}
+
+// The newest download for a sysroot fetched earlier, from the source,
+// release and libc it was fetched with
+pub fn latest_url(fetched: &[String; 3], qemu: &str) -> Option<String> {
+    let source = FetchSource::from_str(&fetched[0], true).ok()?;
+    Some(source_url(
+        source,
+        source_arch(source, qemu),
+        &fetched[1],
+        &fetched[2],
+    ))
+}
+
+pub fn fetch(
+    source: FetchSource,
+    arch: &str,
+    release: Option<String>,
+    libc: Option<String>,
+    name: Option<String>,
+) {
+    let qemu = arch::qemu_name(arch)
+        .unwrap_or_else(|| panic!("{} is not an architecture qemu can emulate.", arch));
+    let source_name = source_name(source);
+    let source_arch = source_arch(source, qemu);
+
+    let release = release.unwrap_or_else(|| {
+        String::from(match source {
+            FetchSource::Debian => "trixie",
+            FetchSource::Ubuntu => "24.04",
+            FetchSource::Alpine => "latest-stable",
+            FetchSource::Bootlin => "stable",
+        })
+    });
+    let libc = libc.unwrap_or_else(|| String::from("glibc"));
+
+    let name = name.unwrap_or_else(|| match source {
+        FetchSource::Bootlin => format!("{}-{}-{}-{}", source_name, libc, release, qemu),
+        _ => format!("{}-{}-{}", source_name, release, qemu),
+    });
+    let dir = sysroot_dir(&name);
+
+    let url = source_url(source, source_arch, &release, &libc);
+    let archive = download(&url, Some(&published_sha256(source, &url)));
+    install(&dir, |partial| extract(&archive, partial));
+
+    let path = match source {
+        FetchSource::Bootlin => {
+            toolchain_sysroot(&dir).unwrap_or_else(|| panic!("{} has no sysroot in it.", url))
+        }
+        _ => dir,
+    };
+    let origin = Origin {
+        source: url,
+        fetch: Some([source_name.to_string(), release, libc]),
+    };
+    sysroot::register(&name, &path, Some(qemu), Some(origin));
+    println!("Added {} as {}.", path.display(), name);
+}
+
+// The layers of a container image flattened into a sysroot, by creating a
+// container which is never started and exporting its filesystem
+pub fn from_image(image: &str, arch: Option<&str>, name: Option<String>) {
+    let qemu = arch.map(|arch| {
+        arch::qemu_name(arch)
+            .unwrap_or_else(|| panic!("{} is not an architecture qemu can emulate.", arch))
+    });
+    let engine = ["podman", "docker"]
+        .into_iter()
+        .find(|engine| foreign::in_path(engine))
+        .unwrap_or_else(|| {
+            panic!(
+                "Neither podman nor docker is installed, one of them is needed to pull {}.",
+                image
+            )
+        });
+
+    let name = name.unwrap_or_else(|| image.replace(['/', ':', '@'], "-"));
+    let dir = sysroot_dir(&name);
+
+    let mut create = Command::new(engine);
+    create.arg("create");
+    if let Some(qemu) = qemu {
+        let platform = NAMES
+            .iter()
+            .find(|names| names.qemu == qemu)
+            .and_then(|names| names.oci)
+            .unwrap_or_else(|| panic!("There are no container images for {}.", qemu));
+        create.arg(format!("--platform=linux/{}", platform));
+    }
+    // Images without a default command need one to create a container
+    create.arg(image).arg("/bin/sh");
+    let output = create
+        .stderr(stderr())
+        .output()
+        .unwrap_or_else(|e| panic!("Unable to run {}: {}", engine, e));
+    if !output.status.success() {
+        panic!(
+            "{} create {} failed ({}).{}",
+            engine,
+            image,
+            output.status,
+            failure(&output.stderr)
+        );
+    }
+    let container = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    let cache = cache_dir();
+    fs::create_dir_all(&cache)
+        .unwrap_or_else(|e| panic!("Unable to create {}: {}", cache.display(), e));
+    let archive = cache.join(format!("{}.tar", name));
+    let exported = Command::new(engine)
+        .arg("export")
+        .arg("-o")
+        .arg(&archive)
+        .arg(&container)
+        .stdout(Stdio::null())
+        .stderr(stderr())
+        .output();
+    let _ = Command::new(engine)
+        .args(["rm", &container])
+        .stdout(Stdio::null())
+        .stderr(stderr())
+        .output();
+    match exported {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => panic!(
+            "{} export failed ({}).{}",
+            engine,
+            output.status,
+            failure(&output.stderr)
+        ),
+        Err(e) => panic!("Unable to run {}: {}", engine, e),
+    }
+
+    install(&dir, |partial| extract(&archive, partial));
+    let _ = fs::remove_file(&archive);
+
+    let origin = Origin {
+        source: image.to_string(),
+        fetch: None,
+    };
+    sysroot::register(&name, &dir, qemu, Some(origin));
+    println!("Added {} as {}.", dir.display(), name);
+}
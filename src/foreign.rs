@@ -0,0 +1,322 @@
+// Executables which aren't ELF are handed to whatever runs them natively
+// so emu stays the single entry point for foreign binaries.
+
+use log::info;
+use serde_json::json;
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::process::Command;
+
+use crate::cli::OutputFormat;
+use crate::shell;
+
+const IMAGE_FILE_MACHINE_I386: u16 = 0x14c;
+const IMAGE_FILE_MACHINE_ARMNT: u16 = 0x1c4;
+const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+const IMAGE_FILE_MACHINE_ARM64: u16 = 0xaa64;
+
+const MH_MAGIC: u32 = 0xfeedface;
+const MH_MAGIC_64: u32 = 0xfeedfacf;
+const FAT_MAGIC: u32 = 0xcafebabe;
+
+// cputype, 64-bit variants set CPU_ARCH_ABI64
+const CPU_TYPE_X86: u32 = 7;
+const CPU_TYPE_X86_64: u32 = 0x01000007;
+const CPU_TYPE_ARM: u32 = 12;
+const CPU_TYPE_ARM64: u32 = 0x0100000c;
+const CPU_TYPE_ARM64_32: u32 = 0x0200000c;
+const CPU_TYPE_POWERPC: u32 = 18;
+const CPU_TYPE_POWERPC64: u32 = 0x01000012;
+
+const MH_OBJECT: u32 = 1;
+const MH_EXECUTE: u32 = 2;
+const MH_DYLIB: u32 = 6;
+const MH_BUNDLE: u32 = 8;
+
+const LC_VERSION_MIN_MACOSX: u32 = 0x24;
+const LC_VERSION_MIN_IPHONEOS: u32 = 0x25;
+const LC_VERSION_MIN_TVOS: u32 = 0x2f;
+const LC_VERSION_MIN_WATCHOS: u32 = 0x30;
+const LC_BUILD_VERSION: u32 = 0x32;
+
+// Optional header magic
+const PE32_MAGIC: u16 = 0x10b;
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+
+enum Format {
+    // wide is set for PE32+
+    Pe { machine: u16, wide: bool },
+    Wasm,
+    // Human readable description, e.g. "arm64 macOS executable"
+    MachO(String),
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn u32_be_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+type Reader = fn(&[u8], usize) -> Option<u32>;
+
+// MZ header, e_lfanew at 0x3c points to "PE\0\0" followed by the COFF
+// header and the optional header.
+fn parse_pe(data: &[u8]) -> Option<Format> {
+    if !data.starts_with(b"MZ") {
+        return None;
+    }
+    let pe = u32_at(data, 0x3c)? as usize;
+    if data.get(pe..pe + 4)? != b"PE\0\0" {
+        return None;
+    }
+
+    let machine = u16_at(data, pe + 4)?;
+    let wide = match u16_at(data, pe + 24)? {
+        PE32_MAGIC => false,
+        PE32_PLUS_MAGIC => true,
+        _ => return None,
+    };
+
+    Some(Format::Pe { machine, wide })
+}
+
+fn parse_wasm(data: &[u8]) -> Option<Format> {
+    // Magic followed by the binary format version
+    if data.starts_with(b"\0asm") && u32_at(data, 4)? == 1 {
+        return Some(Format::Wasm);
+    }
+    None
+}
+
+fn macho_cpu_name(cputype: u32) -> String {
+    match cputype {
+        CPU_TYPE_X86 => String::from("i386"),
+        CPU_TYPE_X86_64 => String::from("x86_64"),
+        CPU_TYPE_ARM => String::from("arm"),
+        CPU_TYPE_ARM64 => String::from("arm64"),
+        CPU_TYPE_ARM64_32 => String::from("arm64_32"),
+        CPU_TYPE_POWERPC => String::from("ppc"),
+        CPU_TYPE_POWERPC64 => String::from("ppc64"),
+        _ => format!("cputype {:#x}", cputype),
+    }
+}
+
+// Platform from the load commands, only the first 4k are looked at which
+// always covers them for executables.
+fn macho_platform(data: &[u8], read: Reader, header_size: usize) -> &'static str {
+    let ncmds = read(data, 16).unwrap_or(0);
+    let mut pos = header_size;
+    for _ in 0..ncmds {
+        let (cmd, cmdsize) = match (read(data, pos), read(data, pos + 4)) {
+            (Some(cmd), Some(cmdsize)) if cmdsize >= 8 => (cmd, cmdsize as usize),
+            _ => break,
+        };
+        match cmd {
+            LC_BUILD_VERSION => {
+                return match read(data, pos + 8) {
+                    Some(1) => "macOS",
+                    Some(2) | Some(7) => "iOS",
+                    Some(3) | Some(8) => "tvOS",
+                    Some(4) | Some(9) => "watchOS",
+                    Some(6) => "Mac Catalyst",
+                    _ => "Apple",
+                }
+            }
+            LC_VERSION_MIN_MACOSX => return "macOS",
+            LC_VERSION_MIN_IPHONEOS => return "iOS",
+            LC_VERSION_MIN_TVOS => return "tvOS",
+            LC_VERSION_MIN_WATCHOS => return "watchOS",
+            _ => {}
+        }
+        pos += cmdsize;
+    }
+    "Apple"
+}
+
+fn parse_macho(data: &[u8]) -> Option<Format> {
+    // Universal binaries share the magic with Java class files, which have
+    // a major version of at least 45 where nfat_arch would be.
+    if u32_be_at(data, 0)? == FAT_MAGIC {
+        let count = u32_be_at(data, 4)?;
+        if count == 0 || count >= 45 {
+            return None;
+        }
+        let cpus: Vec<String> = (0..count as usize)
+            .filter_map(|index| u32_be_at(data, 8 + index * 20))
+            .map(macho_cpu_name)
+            .collect();
+        return Some(Format::MachO(format!(
+            "universal ({}) binary",
+            cpus.join(", ")
+        )));
+    }
+
+    // Thin binaries are in the target's byte order
+    let (read, header_size): (Reader, usize) = match u32_be_at(data, 0)? {
+        MH_MAGIC => (u32_be_at, 28),
+        MH_MAGIC_64 => (u32_be_at, 32),
+        magic if magic.swap_bytes() == MH_MAGIC => (u32_at, 28),
+        magic if magic.swap_bytes() == MH_MAGIC_64 => (u32_at, 32),
+        _ => return None,
+    };
+
+    let kind = match read(data, 12)? {
+        MH_OBJECT => "object file",
+        MH_EXECUTE => "executable",
+        MH_DYLIB => "dynamic library",
+        MH_BUNDLE => "bundle",
+        _ => "binary",
+    };
+
+    Some(Format::MachO(format!(
+        "{} {} {}",
+        macho_cpu_name(read(data, 4)?),
+        macho_platform(data, read, header_size),
+        kind
+    )))
+}
+
+fn detect(path: &str) -> Result<Option<Format>, io::Error> {
+    // Enough for the PE header of anything a linker produces
+    let mut data = Vec::with_capacity(4096);
+    File::open(path)?.take(4096).read_to_end(&mut data)?;
+
+    Ok(parse_pe(&data)
+        .or_else(|| parse_wasm(&data))
+        .or_else(|| parse_macho(&data)))
+}
+
+fn pe_machine_name(machine: u16) -> String {
+    match machine {
+        IMAGE_FILE_MACHINE_I386 => String::from("x86"),
+        IMAGE_FILE_MACHINE_AMD64 => String::from("x86-64"),
+        IMAGE_FILE_MACHINE_ARMNT => String::from("arm"),
+        IMAGE_FILE_MACHINE_ARM64 => String::from("arm64"),
+        _ => format!("machine {:#x}", machine),
+    }
+}
+
+pub fn in_path(name: &str) -> bool {
+    env::split_paths(&env::var_os("PATH").unwrap_or_default()).any(|dir| dir.join(name).is_file())
+}
+
+// --dry-run prints the command in the given format instead of running it
+fn status(
+    mut command: Command,
+    program: &str,
+    dry_run: Option<OutputFormat>,
+    error: impl FnOnce(io::Error) -> String,
+) -> i32 {
+    if let Some(format) = dry_run {
+        shell::print(&command, format, json!({ "program": program }));
+        return 0;
+    }
+
+    info!("running {}", shell::command_line(&command));
+    shell::exit_code(command.status().unwrap_or_else(|e| panic!("{}", error(e))))
+}
+
+fn run_pe(machine: u16, wide: bool, args: &[String], dry_run: Option<OutputFormat>) -> i32 {
+    if machine != IMAGE_FILE_MACHINE_I386 && machine != IMAGE_FILE_MACHINE_AMD64 {
+        panic!(
+            "{} is a Windows {} executable, wine can only run x86 ones.",
+            args[1],
+            pe_machine_name(machine)
+        );
+    }
+
+    // Older wine releases ship a separate wine64 loader, newer ones handle
+    // both with wine.
+    let mut wine = Command::new(if wide && in_path("wine64") {
+        "wine64"
+    } else {
+        "wine"
+    });
+
+    if let Ok(prefix) = env::var("EMU_WINEPREFIX") {
+        wine.env("WINEPREFIX", prefix);
+    }
+
+    wine.args(&args[1..]);
+    status(wine, &args[1], dry_run, |e| {
+        format!(
+            "Unable to run wine for {} ({}), is it installed?",
+            args[1], e
+        )
+    })
+}
+
+// Runs the command template from variable, {} is replaced with the program
+// (appended when missing) and the arguments follow.
+fn run_template(
+    variable: &str,
+    template: &str,
+    args: &[String],
+    dry_run: Option<OutputFormat>,
+) -> i32 {
+    let mut command: Vec<String> = template.split_whitespace().map(String::from).collect();
+    if command.is_empty() {
+        panic!("{} is empty.", variable);
+    }
+    match command.iter().position(|part| part == "{}") {
+        Some(index) => command[index] = args[1].clone(),
+        None => command.push(args[1].clone()),
+    }
+
+    let mut runner = Command::new(&command[0]);
+    runner.args(&command[1..]).args(&args[2..]);
+    status(runner, &args[1], dry_run, |e| {
+        format!(
+            "Unable to run {} for {} ({}), check {}.",
+            command[0], args[1], e, variable
+        )
+    })
+}
+
+fn run_wasm(args: &[String], dry_run: Option<OutputFormat>) -> i32 {
+    let template = env::var("EMU_WASM_RUNTIME").unwrap_or_else(|_| {
+        String::from(if !in_path("wasmtime") && in_path("wasmer") {
+            "wasmer run {} --"
+        } else {
+            "wasmtime run {}"
+        })
+    });
+
+    run_template("EMU_WASM_RUNTIME", &template, args, dry_run)
+}
+
+// There is no default, Darling is the only option and needs setting up.
+fn run_macho(description: &str, args: &[String], dry_run: Option<OutputFormat>) -> i32 {
+    match env::var("EMU_MACHO_RUNNER") {
+        Ok(template) => run_template("EMU_MACHO_RUNNER", &template, args, dry_run),
+        Err(_) => panic!(
+            "{} is a Mach-O {}, set EMU_MACHO_RUNNER (e.g. \"darling shell {{}}\") to run it.",
+            args[1], description
+        ),
+    }
+}
+
+// Returns the exit code if the program was a foreign executable and has been
+// run, None if it should be handled as an ELF.
+pub fn run(args: &[String], dry_run: Option<OutputFormat>) -> Option<i32> {
+    match detect(&args[1]).ok()?? {
+        Format::Pe { machine, wide } => Some(run_pe(machine, wide, args, dry_run)),
+        Format::Wasm => Some(run_wasm(args, dry_run)),
+        Format::MachO(description) => Some(run_macho(&description, args, dry_run)),
+    }
+}
@@ -0,0 +1,27 @@
+use log::LevelFilter;
+
+use std::env;
+use std::io::Write;
+
+// -v is info, -vv debug and -vvv trace. Without them EMU_LOG takes an
+// env_logger filter (e.g. EMU_LOG=debug), EMU_VERBOSE=1 is the same as -v.
+// --quiet turns all of it off.
+pub fn init(verbose: u8, quiet: bool) {
+    let mut builder = env_logger::Builder::new();
+    builder.format(|buf, record| writeln!(buf, "emu: {}", record.args()));
+
+    let emu_verbose =
+        env::var_os("EMU_VERBOSE").is_some_and(|value| !value.is_empty() && value != "0");
+
+    match verbose {
+        _ if quiet => builder.filter_level(LevelFilter::Off),
+        0 if env::var_os("EMU_LOG").is_some() => builder.parse_env("EMU_LOG"),
+        0 if emu_verbose => builder.filter_level(LevelFilter::Info),
+        0 => builder.filter_level(LevelFilter::Off),
+        1 => builder.filter_level(LevelFilter::Info),
+        2 => builder.filter_level(LevelFilter::Debug),
+        _ => builder.filter_level(LevelFilter::Trace),
+    };
+
+    builder.init();
+}
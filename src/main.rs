@@ -1,394 +1,1997 @@
-use num_enum::TryFromPrimitive;
-use std::convert::TryFrom;
+mod arch;
+mod archive;
+mod bootstrap;
+mod choice;
+mod cli;
+mod config;
+mod debug;
+mod discover;
+mod elf;
+mod environment;
+mod fetch;
+mod foreign;
+mod logging;
+mod nix;
+mod overlay;
+mod packages;
+mod qemu_log;
+mod report;
+mod shell;
+mod strace;
+mod sysroot;
+mod version;
+
+use clap::Parser;
+use clap::ValueEnum;
+use cli::{Backend, Debugger, LoaderMode, OutputFormat, RunArgs};
+use config::Settings;
+use log::{debug, info};
+use serde_json::json;
+
+use arch::{Target, EM_386, EM_ARM, EM_RISCV, EM_X86_64};
+use elf::{
+    parse_notes, Attribute, ELFClass, ElfInfo, Endian, ET_CORE, ET_DYN, ET_EXEC, ET_REL,
+    NT_GNU_PROPERTY_TYPE_0,
+};
 
 use std::env;
+use std::ffi::OsString;
+use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::prelude::*;
-use std::io::SeekFrom;
-use std::path::Path;
+use std::io::Read;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process;
 use std::process::Command;
 use std::str;
+use std::time::Instant;
 
-static ELF_MAGIC: [u8; 4] = [0x7f, 0x45, 0x4c, 0x46];
+// EI_OSABI values of systems qemu-user can't run executables for. Old ARM
+// toolchains set ELFOSABI_ARM on Linux executables, so it isn't listed.
+static FOREIGN_OSABIS: &[(u8, &str)] = &[
+    (1, "HP-UX"),
+    (2, "NetBSD"),
+    (6, "Solaris"),
+    (7, "AIX"),
+    (8, "IRIX"),
+    (9, "FreeBSD"),
+    (10, "Tru64"),
+    (12, "OpenBSD"),
+    (255, "standalone (bare-metal)"),
+];
 
-#[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, TryFromPrimitive)]
-#[repr(u8)]
-enum ELFClass {
-    ELFCLASS32 = 1,
-    ELFCLASS64,
+#[derive(Debug)]
+enum FloatAbi {
+    Soft,
+    Hard,
 }
 
-#[derive(Debug, TryFromPrimitive)]
-#[repr(u8)]
-enum Endian {
-    Little = 1,
-    Big,
+struct Executable {
+    path: PathBuf,
+    elf: ElfInfo,
+    float_abi: Option<FloatAbi>,
+    // Tag_CPU_arch
+    arm_arch: Option<u64>,
+    // Tag_RISCV_arch
+    riscv_arch: Option<String>,
+    // GNU_PROPERTY_X86_ISA_1_NEEDED
+    x86_isa: u32,
+    target: &'static Target,
 }
 
-#[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, TryFromPrimitive)]
-#[repr(u16)]
-enum Machine {
-    X86 = 3,
-    PPC64 = 21,
-    S390 = 22,
-    ARM = 40,
-    X86_64 = 62,
-    AARCH64 = 183,
-    RISCV = 243,
+// RISC-V float ABI, double is also the fallback for quad
+const EF_RISCV_FLOAT_ABI: u32 = 0x6;
+const EF_RISCV_FLOAT_ABI_SOFT: u32 = 0x0;
+const EF_RISCV_FLOAT_ABI_SINGLE: u32 = 0x2;
+
+// EABI version lives in the top byte of e_flags for ARM, the float ABI flags
+// are only defined from EABI version 5 onwards.
+const EF_ARM_EABIMASK: u32 = 0xff000000;
+const EF_ARM_EABI_VER5: u32 = 0x05000000;
+const EF_ARM_ABI_FLOAT_SOFT: u32 = 0x200;
+const EF_ARM_ABI_FLOAT_HARD: u32 = 0x400;
+
+// .ARM.attributes tags
+const TAG_ARM_CPU_RAW_NAME: u64 = 4;
+const TAG_ARM_CPU_NAME: u64 = 5;
+const TAG_ARM_CPU_ARCH: u64 = 6;
+const TAG_ARM_ABI_VFP_ARGS: u64 = 28;
+const TAG_ARM_CONFORMANCE: u64 = 67;
+
+// .riscv.attributes tags
+const TAG_RISCV_ARCH: u64 = 5;
+
+// ISA extensions qemu has a riscv cpu property for, anything else in the
+// architecture string is left to the base cpu model.
+#[rustfmt::skip]
+static QEMU_RISCV_EXTENSIONS: &[&str] = &[
+    "a", "c", "d", "f", "h", "m", "v", "zawrs", "zba", "zbb", "zbc", "zbkb", "zbkc", "zbkx", "zbs",
+    "zca", "zcb", "zcd", "zce", "zcf", "zcmp", "zcmt", "zdinx", "zfa", "zfh", "zfhmin", "zfinx",
+    "zhinx", "zhinxmin", "zicbom", "zicbop", "zicboz", "zicond", "zicntr", "zicsr", "zifencei",
+    "zihintntl", "zihintpause", "zihpm", "zk", "zkn", "zknd", "zkne", "zknh", "zkr", "zks",
+    "zksed", "zksh", "zkt", "zmmul", "zvbb", "zvbc", "zve32f", "zve32x", "zve64d", "zve64f",
+    "zve64x", "zvfh", "zvfhmin", "zvkb", "zvkg", "zvkn", "zvknc", "zvkned", "zvkng", "zvknha",
+    "zvknhb", "zvks", "zvksc", "zvksed", "zvksg", "zvksh", "zvkt",
+];
+
+const GNU_PROPERTY_X86_ISA_1_NEEDED: u32 = 0xc0008002;
+const GNU_PROPERTY_X86_ISA_1_V4: u32 = 1 << 3;
+
+fn arm_float_abi(flags: u32, attributes: &[(u64, Attribute)]) -> Option<FloatAbi> {
+    if flags & EF_ARM_EABIMASK >= EF_ARM_EABI_VER5 {
+        if flags & EF_ARM_ABI_FLOAT_HARD != 0 {
+            return Some(FloatAbi::Hard);
+        }
+        if flags & EF_ARM_ABI_FLOAT_SOFT != 0 {
+            return Some(FloatAbi::Soft);
+        }
+    }
+
+    // Older toolchains don't set the e_flags, fall back to the build attributes
+    if attributes.is_empty() {
+        return None;
+    }
+
+    for (tag, value) in attributes {
+        if let (TAG_ARM_ABI_VFP_ARGS, Attribute::Int(args)) = (*tag, value) {
+            // 1 means arguments are passed in VFP registers
+            return Some(if *args == 1 {
+                FloatAbi::Hard
+            } else {
+                FloatAbi::Soft
+            });
+        }
+    }
+
+    // Tag_ABI_VFP_args defaults to the base (soft-float) procedure call standard
+    Some(FloatAbi::Soft)
 }
 
-struct Executable {
-    class: ELFClass,
-    endian: Endian,
-    loader: String,
-    machine: Machine,
+fn arm_cpu_arch(attributes: &[(u64, Attribute)]) -> Option<u64> {
+    attributes
+        .iter()
+        .find_map(|(tag, value)| match (*tag, value) {
+            (TAG_ARM_CPU_ARCH, Attribute::Int(arch)) => Some(*arch),
+            _ => None,
+        })
 }
 
-macro_rules! unpack {
-    ($bytes:expr, $inttype:ty, $endian:expr) => {
-        match $endian {
-            Endian::Little => <$inttype>::from_le_bytes($bytes),
-            Endian::Big => <$inttype>::from_be_bytes($bytes),
+// GNU_PROPERTY_X86_ISA_1_NEEDED bitmask from the GNU property note, 0 when
+// the binary doesn't record it.
+fn x86_isa_needed(data: &[u8], class: &ELFClass, endian: &Endian) -> u32 {
+    // Properties are padded to the native word size
+    let align = match class {
+        ELFClass::ELFCLASS32 => 4,
+        ELFClass::ELFCLASS64 => 8,
+    };
+
+    for desc in parse_notes(data, endian, b"GNU\0", NT_GNU_PROPERTY_TYPE_0) {
+        let mut pos = 0;
+        while pos + 8 <= desc.len() {
+            let pr_type: u32 = unpack!(desc[pos..pos + 4].try_into().unwrap(), u32, endian);
+            let pr_datasz =
+                unpack!(desc[pos + 4..pos + 8].try_into().unwrap(), u32, endian) as usize;
+            if pos + 8 + pr_datasz > desc.len() {
+                break;
+            }
+            if pr_type == GNU_PROPERTY_X86_ISA_1_NEEDED && pr_datasz >= 4 {
+                return unpack!(desc[pos + 8..pos + 12].try_into().unwrap(), u32, endian);
+            }
+            pos += (8 + pr_datasz + align - 1) & !(align - 1);
         }
+    }
+
+    0
+}
+
+fn riscv_arch(attributes: &[(u64, Attribute)]) -> Option<String> {
+    attributes
+        .iter()
+        .find_map(|(tag, value)| match (*tag, value) {
+            (TAG_RISCV_ARCH, Attribute::Str(arch)) => Some(arch.clone()),
+            _ => None,
+        })
+}
+
+// Split an ISA string like rv64i2p1_m2p0_a2p1_c2p0_v1p0_zba1p0 into its
+// extension names, dropping the version numbers.
+fn riscv_extensions(arch: &str) -> Vec<String> {
+    let mut extensions = Vec::new();
+    let arch = arch.to_lowercase();
+    let arch = arch
+        .strip_prefix("rv32")
+        .or_else(|| arch.strip_prefix("rv64"))
+        .unwrap_or("");
+
+    for (index, token) in arch.split('_').enumerate() {
+        if index == 0 || token.len() == 1 {
+            // Single letter extensions, each optionally followed by a version
+            let chars: Vec<char> = token.chars().collect();
+            let mut pos = 0;
+            while pos < chars.len() {
+                let extension = chars[pos];
+                pos += 1;
+                let version_start = pos;
+                while pos < chars.len() && chars[pos].is_ascii_digit() {
+                    pos += 1;
+                }
+                if pos > version_start
+                    && pos + 1 < chars.len()
+                    && chars[pos] == 'p'
+                    && chars[pos + 1].is_ascii_digit()
+                {
+                    pos += 1;
+                    while pos < chars.len() && chars[pos].is_ascii_digit() {
+                        pos += 1;
+                    }
+                }
+                extensions.push(extension.to_string());
+            }
+        } else {
+            // Multi letter extensions end with an optional <major>p<minor> version
+            let mut name = token.trim_end_matches(|c: char| c.is_ascii_digit());
+            if name.len() < token.len() {
+                if let Some(major) = name.strip_suffix('p') {
+                    let stripped = major.trim_end_matches(|c: char| c.is_ascii_digit());
+                    if stripped.len() < major.len() {
+                        name = stripped;
+                    }
+                }
+            }
+            extensions.push(name.to_string());
+        }
+    }
+
+    extensions
+}
+
+// Directories (relative to the sysroot root and its usr/) searched for libraries,
+// in priority order.
+fn library_dirs(executable: &Executable) -> Vec<&'static str> {
+    let mut dirs = Vec::new();
+
+    match (executable.elf.machine, executable.elf.class) {
+        // glibc installs RISC-V libraries into an ABI subdirectory (lib64/lp64d,
+        // lib32/ilp32), the target's plain directories are the fallback for
+        // distributions which don't use it.
+        (EM_RISCV, ELFClass::ELFCLASS64) => {
+            dirs.push(match executable.elf.flags & EF_RISCV_FLOAT_ABI {
+                EF_RISCV_FLOAT_ABI_SOFT => "lib64/lp64",
+                EF_RISCV_FLOAT_ABI_SINGLE => "lib64/lp64f",
+                _ => "lib64/lp64d",
+            })
+        }
+        (EM_RISCV, ELFClass::ELFCLASS32) => {
+            dirs.push(match executable.elf.flags & EF_RISCV_FLOAT_ABI {
+                EF_RISCV_FLOAT_ABI_SOFT => "lib32/ilp32",
+                EF_RISCV_FLOAT_ABI_SINGLE => "lib32/ilp32f",
+                _ => "lib32/ilp32d",
+            })
+        }
+        _ => {}
+    }
+
+    // Debian puts everything into the multiarch directory, ld.so.conf lists
+    // it before the rest
+    dirs.extend(multiarch_dir(executable));
+    dirs.extend(executable.target.lib_dirs);
+    dirs
+}
+
+// lib/<multiarch tuple> of Debian based sysroots (debootstrap, cross
+// toolchain packages)
+fn multiarch_dir(executable: &Executable) -> Option<&'static str> {
+    let dir = match (executable.target.qemu, executable.elf.class) {
+        ("aarch64", ELFClass::ELFCLASS64) => "lib/aarch64-linux-gnu",
+        ("alpha", _) => "lib/alpha-linux-gnu",
+        ("arc", _) => "lib/arc-linux-gnu",
+        // ARM libraries are in a directory named after the float ABI
+        ("arm", _) => match executable.float_abi {
+            Some(FloatAbi::Hard) => "lib/arm-linux-gnueabihf",
+            Some(FloatAbi::Soft) => "lib/arm-linux-gnueabi",
+            None => return None,
+        },
+        ("hppa", _) => "lib/hppa-linux-gnu",
+        ("i386", _) => "lib/i386-linux-gnu",
+        ("loongarch64", _) => "lib/loongarch64-linux-gnu",
+        ("m68k", _) => "lib/m68k-linux-gnu",
+        ("mips", _) => "lib/mips-linux-gnu",
+        ("mipsel", _) => "lib/mipsel-linux-gnu",
+        ("mipsn32", _) => "lib/mips64-linux-gnuabin32",
+        ("mipsn32el", _) => "lib/mips64el-linux-gnuabin32",
+        ("mips64", _) => "lib/mips64-linux-gnuabi64",
+        ("mips64el", _) => "lib/mips64el-linux-gnuabi64",
+        ("ppc", _) => "lib/powerpc-linux-gnu",
+        ("ppc64", _) => "lib/powerpc64-linux-gnu",
+        ("ppc64le", _) => "lib/powerpc64le-linux-gnu",
+        ("riscv64", _) => "lib/riscv64-linux-gnu",
+        ("s390x", _) => "lib/s390x-linux-gnu",
+        ("sh4", _) => "lib/sh4-linux-gnu",
+        ("sparc64", _) => "lib/sparc64-linux-gnu",
+        ("x86_64", ELFClass::ELFCLASS64) => "lib/x86_64-linux-gnu",
+        ("x86_64", ELFClass::ELFCLASS32) => "lib/x86_64-linux-gnux32",
+        _ => return None,
     };
+    Some(dir)
+}
+
+// Without the compatibility symlinks (/lib/ld-linux-aarch64.so.1) a Debian
+// sysroot only has the loader in the multiarch directory.
+fn multiarch_loader(sysroot: &Path, executable: &Executable, interpreter: &str) -> Option<PathBuf> {
+    let dir = multiarch_dir(executable)?;
+    let name = Path::new(interpreter).file_name()?.to_str()?;
+    [
+        format!("/{}/{}", dir, name),
+        format!("/usr/{}/{}", dir, name),
+    ]
+    .iter()
+    .find_map(|path| resolve_in_sysroot(sysroot, Path::new(path)))
 }
 
-fn run_executable(executable: Executable, args: &[String]) {
-    let qemu_suffix: &str = match executable.machine {
-        Machine::AARCH64 => "aarch64",
-        Machine::ARM => "arm",
-        Machine::PPC64 => match executable.endian {
-            Endian::Big => "ppc64",
-            Endian::Little => "ppc64le",
+// The loader a glibc sysroot for this executable's ABI would provide.
+fn default_loader(executable: &Executable) -> &'static str {
+    match (executable.elf.machine, executable.elf.class) {
+        (EM_ARM, _) => match executable.float_abi {
+            Some(FloatAbi::Soft) => "/lib/ld-linux.so.3",
+            _ => executable.target.loader,
         },
-        Machine::RISCV => match executable.class {
-            ELFClass::ELFCLASS32 => "riscv32",
-            ELFClass::ELFCLASS64 => "riscv64",
+        (EM_RISCV, ELFClass::ELFCLASS64) => match executable.elf.flags & EF_RISCV_FLOAT_ABI {
+            EF_RISCV_FLOAT_ABI_SOFT => "/lib/ld-linux-riscv64-lp64.so.1",
+            _ => executable.target.loader,
         },
-        Machine::S390 => match executable.class {
-            ELFClass::ELFCLASS32 => "s390",
-            ELFClass::ELFCLASS64 => "s390x",
+        (EM_RISCV, ELFClass::ELFCLASS32) => match executable.elf.flags & EF_RISCV_FLOAT_ABI {
+            EF_RISCV_FLOAT_ABI_SOFT => "/lib/ld-linux-riscv32-ilp32.so.1",
+            _ => executable.target.loader,
         },
-        Machine::X86 => "i386",
-        Machine::X86_64 => "x86_64",
+        _ => executable.target.loader,
+    }
+}
+
+fn cpu_model(executable: &Executable) -> String {
+    if let Some(arch) = &executable.riscv_arch {
+        let mut cpu = String::from(match executable.elf.class {
+            ELFClass::ELFCLASS32 => "rv32",
+            ELFClass::ELFCLASS64 => "rv64",
+        });
+        let mut vlen = 0;
+        for extension in riscv_extensions(arch) {
+            if QEMU_RISCV_EXTENSIONS.contains(&extension.as_str()) {
+                cpu.push_str(&format!(",{}=true", extension));
+            } else if let Some(bits) = extension
+                .strip_prefix("zvl")
+                .and_then(|bits| bits.strip_suffix('b'))
+                .and_then(|bits| bits.parse::<u32>().ok())
+            {
+                // Zvl<N>b is the minimum vector length the code relies on
+                vlen = vlen.max(bits);
+            }
+        }
+        // qemu defaults to a vector length of 128 bits
+        if vlen > 128 {
+            cpu.push_str(&format!(",vlen={}", vlen));
+        }
+        return cpu;
+    }
+
+    if let Some(arch) = executable.arm_arch {
+        // Tag_CPU_arch values, ARMv8 and M profile binaries stay on max
+        let cpu = match arch {
+            // ARMv4, ARMv4T
+            1 | 2 => Some("ti925t"),
+            // ARMv5T, ARMv5TE, ARMv5TEJ
+            3..=5 => Some("arm926"),
+            // ARMv6
+            6 => Some("arm1136"),
+            // ARMv6KZ
+            7 => Some("arm1176"),
+            // ARMv6K
+            9 => Some("arm11mpcore"),
+            // ARMv6T2 has Thumb-2, the oldest qemu model with it is ARMv7
+            8 => Some("cortex-a8"),
+            // ARMv7
+            10 => Some("cortex-a15"),
+            _ => None,
+        };
+        if let Some(cpu) = cpu {
+            return String::from(cpu);
+        }
+    }
+
+    String::from("max")
+}
+
+// musl loaders are named ld-musl-<arch>.so.1, the arch part also names the
+// search path file in /etc.
+fn musl_arch(executable: &Executable) -> Option<&str> {
+    Path::new(&executable.elf.interpreter)
+        .file_name()?
+        .to_str()?
+        .strip_prefix("ld-musl-")?
+        .strip_suffix(".so.1")
+}
+
+fn is_bionic(executable: &Executable) -> bool {
+    executable.elf.interpreter == "/system/bin/linker"
+        || executable.elf.interpreter == "/system/bin/linker64"
+}
+
+// Absolute directories inside the sysroot the loader will search, in order.
+fn search_dirs(sysroot: &Path, executable: &Executable) -> Vec<String> {
+    if is_bionic(executable) {
+        // Android splits the platform libraries between /system and the
+        // runtime/ART APEXes, bionic itself lives in com.android.runtime.
+        let lib = match executable.elf.class {
+            ELFClass::ELFCLASS32 => "lib",
+            ELFClass::ELFCLASS64 => "lib64",
+        };
+        return [
+            "/apex/com.android.runtime/{lib}/bionic",
+            "/system/{lib}",
+            "/apex/com.android.art/{lib}",
+            "/apex/com.android.i18n/{lib}",
+            "/vendor/{lib}",
+        ]
+        .iter()
+        .map(|dir| dir.replace("{lib}", lib))
+        .collect();
+    }
+
+    if let Some(arch) = musl_arch(executable) {
+        // musl doesn't use multilib directories, it reads the search path from
+        // /etc/ld-musl-<arch>.path (newline or colon separated) and falls back
+        // to a fixed list.
+        let path_file = format!("/etc/ld-musl-{}.path", arch);
+        if let Some(contents) = resolve_in_sysroot(sysroot, Path::new(&path_file))
+            .and_then(|path| fs::read_to_string(path).ok())
+        {
+            return contents
+                .split(['\n', ':'])
+                .map(str::trim)
+                .filter(|dir| dir.starts_with('/'))
+                .map(String::from)
+                .collect();
+        }
+        return ["/lib", "/usr/local/lib", "/usr/lib"]
+            .iter()
+            .map(|dir| dir.to_string())
+            .collect();
+    }
+
+    // ld.so.cache is built from ld.so.conf, the default directories are
+    // only searched after it
+    let mut dirs = ld_so_conf(sysroot);
+    for dir in library_dirs(executable)
+        .iter()
+        .flat_map(|dir| [format!("/usr/{}", dir), format!("/{}", dir)])
+    {
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    dirs
+}
+
+// Directories listed in the sysroot's /etc/ld.so.conf, following include
+// lines (include /etc/ld.so.conf.d/*.conf) like ldconfig does.
+fn ld_so_conf(sysroot: &Path) -> Vec<String> {
+    let mut dirs = Vec::new();
+    read_ld_so_conf(sysroot, Path::new("/etc/ld.so.conf"), &mut dirs, 0);
+    dirs
+}
+
+fn read_ld_so_conf(sysroot: &Path, path: &Path, dirs: &mut Vec<String>, depth: u32) {
+    // Files can include each other
+    if depth > 8 {
+        return;
+    }
+    let contents =
+        match resolve_in_sysroot(sysroot, path).and_then(|path| fs::read_to_string(path).ok()) {
+            Some(contents) => contents,
+            None => return,
+        };
+
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if let Some(patterns) = line.strip_prefix("include ") {
+            for pattern in patterns.split_whitespace() {
+                // Relative to the including file's directory
+                let pattern = path.parent().unwrap_or(Path::new("/")).join(pattern);
+                for include in glob_in_sysroot(sysroot, &pattern) {
+                    read_ld_so_conf(sysroot, &include, dirs, depth + 1);
+                }
+            }
+        } else if !line.starts_with("hwcap ") {
+            for dir in line
+                .split([' ', '\t', ',', ':'])
+                .filter(|dir| dir.starts_with('/'))
+            {
+                let dir = match dir.trim_end_matches('/') {
+                    "" => "/",
+                    dir => dir,
+                };
+                if !dirs.iter().any(|known| known == dir) {
+                    dirs.push(dir.to_string());
+                }
+            }
+        }
+    }
+}
+
+// Paths inside the sysroot matching pattern, sorted. Only * in the last
+// component is supported, which is all ld.so.conf files use.
+fn glob_in_sysroot(sysroot: &Path, pattern: &Path) -> Vec<PathBuf> {
+    let dir = pattern.parent().unwrap_or(Path::new("/"));
+    let name = pattern
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    if !name.contains('*') {
+        return vec![pattern.to_path_buf()];
+    }
+
+    let mut matches: Vec<PathBuf> = resolve_in_sysroot(sysroot, dir)
+        .and_then(|dir| fs::read_dir(dir).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|candidate| wildcard_match(name, candidate))
+        .map(|name| dir.join(name))
+        .collect();
+    matches.sort();
+    matches
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
     };
 
-    // On Ubuntu executables are named as qemu-<arch>-static
-    let mut static_suffix: &str = "";
-    let qemu_static_path = format!("/usr/bin/qemu-{}-static", qemu_suffix);
-    if Path::new(&qemu_static_path).exists() {
-        static_suffix = "-static";
+    let parts: Vec<&str> = parts.collect();
+    for (index, part) in parts.iter().enumerate() {
+        if index == parts.len() - 1 {
+            return rest.len() >= part.len() && rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(position) => rest = &rest[position + part.len()..],
+            None => return false,
+        }
     }
+    rest.is_empty()
+}
 
-    let sysroot = env::var("EMU_SYSROOT").unwrap_or_default();
-    if !sysroot.is_empty() {
-        //println!("Sysroot: {}, Loader: {}", sysroot, executable.loader);
+// DT_RPATH/DT_RUNPATH entries as host paths. $ORIGIN is the directory of the
+// executable, absolute entries are inside the sysroot unless in the Nix store.
+fn expand_rpath(sysroot: &str, executable: &Executable, entries: &[String]) -> Vec<String> {
+    let origin = executable
+        .path
+        .parent()
+        .map(|dir| dir.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    entries
+        .iter()
+        .flat_map(|entry| entry.split(':'))
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| {
+            if dir.starts_with("$ORIGIN") || dir.starts_with("${ORIGIN}") {
+                dir.replacen("${ORIGIN}", &origin, 1)
+                    .replacen("$ORIGIN", &origin, 1)
+            } else if nix::is_store_path(Path::new(dir)) {
+                dir.to_string()
+            } else if dir.starts_with('/') {
+                format!("{}{}", sysroot, dir)
+            } else {
+                // Relative to the working directory, as ld.so does
+                dir.to_string()
+            }
+        })
+        .collect()
+}
+
+// Host directories in the order the loader should search them, DT_RPATH is
+// only used when there is no DT_RUNPATH and comes before the library path.
+fn library_search_path(sysroot: &str, executable: &Executable, settings: &Settings) -> Vec<String> {
+    let mut dirs = Vec::new();
+    if executable.elf.runpath.is_empty() {
+        dirs.extend(expand_rpath(sysroot, executable, &executable.elf.rpath));
+    }
+    dirs.extend(
+        search_dirs(Path::new(sysroot), executable)
+            .iter()
+            .map(|dir| format!("{}{}", sysroot, dir)),
+    );
+    dirs.extend(expand_rpath(sysroot, executable, &executable.elf.runpath));
+    dirs.extend(
+        settings
+            .library_path
+            .iter()
+            .map(|dir| match dir.strip_prefix('=') {
+                Some(dir) => format!("{}{}", sysroot, dir),
+                None => dir.clone(),
+            }),
+    );
+    dirs
+}
+
+fn library_path(sysroot: &str, executable: &Executable, settings: &Settings) -> String {
+    library_search_path(sysroot, executable, settings).join(":")
+}
+
+// Resolve path the way the kernel would inside a chroot at sysroot. Loaders are
+// often absolute symlinks (/lib/ld-linux-aarch64.so.1 -> /usr/lib64/...) which
+// would otherwise point into the host filesystem.
+fn resolve_in_sysroot(sysroot: &Path, path: &Path) -> Option<PathBuf> {
+    find_in_sysroot(sysroot, path).ok()
+}
+
+// Like resolve_in_sysroot, the error is the host path which was missing
+fn find_in_sysroot(sysroot: &Path, path: &Path) -> Result<PathBuf, PathBuf> {
+    const MAX_SYMLINKS: u32 = 40;
+
+    fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+        for component in path.components().rev() {
+            match component {
+                Component::Normal(name) => pending.push(name.to_os_string()),
+                Component::ParentDir => pending.push(OsString::from("..")),
+                _ => {}
+            }
+        }
+    }
+
+    // The Nix store is the same everywhere
+    if nix::is_store_path(path) {
+        return fs::canonicalize(path).map_err(|_| path.to_path_buf());
+    }
+
+    let mut pending = Vec::new();
+    push_components(&mut pending, path);
 
-        if executable.loader.is_empty() {
+    let mut resolved = PathBuf::new();
+    let mut symlinks = 0;
+    while let Some(name) = pending.pop() {
+        if name == ".." {
+            // Can't escape the sysroot, just like / in a chroot
+            resolved.pop();
+            continue;
+        }
+
+        let candidate = resolved.join(&name);
+        let missing = || sysroot.join(&candidate);
+        let metadata = fs::symlink_metadata(sysroot.join(&candidate)).map_err(|_| missing())?;
+        if metadata.file_type().is_symlink() {
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(missing());
+            }
+
+            let target = fs::read_link(sysroot.join(&candidate)).map_err(|_| missing())?;
+            if nix::is_store_path(&target) {
+                let rest: PathBuf = pending.iter().rev().collect();
+                let path = target.join(rest);
+                return fs::canonicalize(&path).map_err(|_| path);
+            }
+            if target.is_absolute() {
+                resolved = PathBuf::new();
+            }
+            push_components(&mut pending, &target);
+        } else {
+            resolved = candidate;
+        }
+    }
+
+    Ok(sysroot.join(resolved))
+}
+
+// A loader for another architecture makes qemu fail with an exec format error
+// which doesn't say which file was wrong.
+fn check_loader(loader: &Path, executable: &Executable) {
+    let elf = File::open(loader).and_then(|mut f| ElfInfo::parse(&mut f));
+    let elf = match elf {
+        Ok(elf) => elf,
+        Err(e) => panic!(
+            "The loader {} can't be used, it {}. Is the sysroot complete?",
+            loader.display(),
+            match e.kind() {
+                io::ErrorKind::InvalidData => e.to_string(),
+                _ => format!("can't be read ({})", e),
+            }
+        ),
+    };
+
+    if elf.machine != executable.elf.machine
+        || elf.class != executable.elf.class
+        || elf.endian != executable.elf.endian
+    {
+        let bits = |class: &ELFClass| match class {
+            ELFClass::ELFCLASS32 => 32,
+            ELFClass::ELFCLASS64 => 64,
+        };
+        panic!(
+            "The loader {} is for {} ({}-bit, {:?} endian) but the executable is {} ({}-bit, {:?} endian). Is the sysroot for a different architecture?",
+            loader.display(),
+            arch::machine_name(elf.machine)
+                .map(String::from)
+                .unwrap_or_else(|| format!("machine {}", elf.machine)),
+            bits(&elf.class),
+            elf.endian,
+            executable.target.qemu,
+            bits(&executable.elf.class),
+            executable.elf.endian
+        );
+    }
+}
+
+// The loader silently skips library directories it can't read
+fn check_library_dirs(sysroot: &Path, executable: &Executable, settings: &Settings) {
+    for dir in library_search_path(&sysroot.to_string_lossy(), executable, settings) {
+        let path = match Path::new(&dir).strip_prefix(sysroot) {
+            Ok(relative) => resolve_in_sysroot(sysroot, relative),
+            Err(_) => Some(PathBuf::from(&dir)),
+        };
+        // Missing directories are normal, not every layout has all of them
+        if let Some(path) = path.filter(|path| path.exists()) {
+            if let Err(e) = fs::read_dir(&path) {
+                panic!("The library directory {} can't be read: {}", dir, e);
+            }
+        }
+    }
+}
+
+// Only direct dependencies are checked, a missing library would otherwise
+// only show up as a loader error from inside qemu.
+fn check_needed_libraries(sysroot: &Path, executable: &Executable, settings: &Settings) {
+    let search_dirs = library_search_path(&sysroot.to_string_lossy(), executable, settings);
+
+    // Directories inside the sysroot have to be resolved like in a chroot
+    let exists = |path: &Path| match path.strip_prefix(sysroot) {
+        Ok(relative) => resolve_in_sysroot(sysroot, relative).is_some(),
+        Err(_) => path.exists(),
+    };
+
+    let missing: Vec<&String> = executable
+        .elf
+        .needed
+        .iter()
+        .filter(|library| {
+            !search_dirs
+                .iter()
+                .any(|dir| exists(&Path::new(dir).join(library.as_str())))
+        })
+        .collect();
+
+    if !missing.is_empty() {
+        let mut message = format!("Missing libraries in {}:\n", sysroot.display());
+        for library in missing {
+            message.push_str(&format!("    {}\n", library));
+        }
+        message.push_str("Searched:\n");
+        for dir in &search_dirs {
+            message.push_str(&format!("    {}\n", dir));
+        }
+        panic!("{}", message.trim_end());
+    }
+}
+
+// qemu reports the host kernel release to the guest, glibc aborts with
+// "FATAL: kernel too old" if that is older than what it was built for.
+fn kernel_release(executable: &Executable) -> Option<String> {
+    let (major, minor, patch) = executable.elf.min_kernel?;
+
+    let host_release = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+    let host: Vec<u32> = host_release
+        .trim()
+        .split(|c: char| !c.is_ascii_digit())
+        .take(3)
+        .map(|part| part.parse().unwrap_or(0))
+        .collect();
+    let host = (
+        host.first().copied().unwrap_or(0),
+        host.get(1).copied().unwrap_or(0),
+        host.get(2).copied().unwrap_or(0),
+    );
+
+    if host >= (major, minor, patch) {
+        return None;
+    }
+
+    Some(format!("{}.{}.{}", major, minor, patch))
+}
+
+// Non-PIE executables have to be mapped at their link address, the host
+// refuses mappings below vm.mmap_min_addr so the guest needs a base offset.
+// --guest-base, EMU_GUEST_BASE and guest-base from the config files come
+// first.
+fn guest_base(executable: &Executable, options: &RunArgs, settings: &Settings) -> Option<String> {
+    if let Some(base) = options
+        .guest_base
+        .clone()
+        .or_else(|| env::var("EMU_GUEST_BASE").ok())
+        .or_else(|| settings.guest_base.clone())
+    {
+        return Some(base);
+    }
+
+    if executable.elf.elf_type != ET_EXEC {
+        return None;
+    }
+
+    let mmap_min_addr = fs::read_to_string("/proc/sys/vm/mmap_min_addr")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(0x10000);
+    if executable.elf.load_address >= mmap_min_addr {
+        return None;
+    }
+
+    Some(String::from("0x1000000000"))
+}
+
+// --cpu, EMU_CPU_<ARCH> (e.g. EMU_CPU_AARCH64), the cpu setting of the
+// config files or the sysroot, otherwise the model for the executable
+fn cpu(executable: &Executable, options: &RunArgs, settings: &Settings) -> String {
+    options
+        .cpu
+        .clone()
+        .or_else(|| env::var(format!("EMU_CPU_{}", executable.target.qemu.to_uppercase())).ok())
+        .or_else(|| settings.cpu.clone())
+        .unwrap_or_else(|| cpu_model(executable))
+}
+
+// Options shared by every way of running the executable
+fn qemu_command(
+    executable: &Executable,
+    qemu: &str,
+    options: &RunArgs,
+    settings: &Settings,
+) -> Command {
+    let mut command = Command::new(qemu);
+    // --reserved-va, EMU_RESERVED_VA, then reserved-va from the config files
+    let reserved_va = options
+        .reserved_va
+        .clone()
+        .or_else(|| env::var("EMU_RESERVED_VA").ok())
+        .or_else(|| settings.reserved_va.clone())
+        .unwrap_or_else(|| String::from("0xf7000000"));
+    command.arg("-R").arg(reserved_va);
+
+    if let Some(base) = guest_base(executable, options, settings) {
+        debug!("guest base {}", base);
+        command.arg("-B").arg(base);
+    }
+
+    let cpu = cpu(executable, options, settings);
+    debug!("cpu model {}", cpu);
+    command.arg("-cpu").arg(cpu);
+
+    if let Some(release) = kernel_release(executable) {
+        debug!(
+            "host kernel is older than {}, reporting it to the guest",
+            release
+        );
+        command.arg("-r").arg(release);
+    }
+
+    if let Some(port) = options.gdb {
+        command.arg("-g").arg(port.to_string());
+    }
+
+    if options.strace.is_some() {
+        command.arg("-strace");
+        if let Some(log) = strace::log_path(options, &executable.path) {
+            command.arg("-D").arg(log);
+        }
+    }
+
+    if let (Some(items), Some(log)) = (
+        &options.qemu_log,
+        qemu_log::path(options, settings, &executable.path),
+    ) {
+        command.arg("-d").arg(items).arg("-D").arg(log);
+    }
+
+    for (name, value) in environment::set(options, settings) {
+        command.arg("-E").arg(format!("{}={}", name, value));
+    }
+    for name in &options.unset_env {
+        command.arg("-U").arg(name);
+    }
+
+    command.args(&settings.qemu_args);
+
+    // EMU_QEMU_ARGS applies to every target, EMU_QEMU_ARGS_<ARCH> (e.g.
+    // EMU_QEMU_ARGS_AARCH64) to one. Both are whitespace separated and come
+    // after the defaults and the config file so they can override them.
+    for variable in [
+        String::from("EMU_QEMU_ARGS"),
+        format!("EMU_QEMU_ARGS_{}", executable.target.qemu.to_uppercase()),
+    ] {
+        if let Ok(qemu_args) = env::var(variable) {
+            command.args(qemu_args.split_whitespace());
+        }
+    }
+
+    // Last so they override everything else
+    command.args(&options.qemu_args);
+
+    command
+}
+
+// --qemu, EMU_QEMU or the config file, otherwise the distribution's qemu-<arch>
+fn qemu_path(executable: &Executable, options: &RunArgs, settings: &Settings) -> String {
+    if let Some(qemu) = options
+        .qemu
+        .clone()
+        .or_else(|| env::var("EMU_QEMU").ok())
+        .or_else(|| settings.qemu.clone())
+    {
+        let exists = if qemu.contains('/') {
+            Path::new(&qemu).exists()
+        } else {
+            foreign::in_path(&qemu)
+        };
+        if !exists {
             panic!(
-                "EMU_SYSROOT is set to {} but this executable defines no loader.\n \
-                This can't work, please unset EMU_SYSROOT variable and re-run the command.",
-                sysroot
+                "{} does not exist, check --qemu, EMU_QEMU and the qemu setting in the config files.",
+                qemu
             );
         }
+        return qemu;
+    }
 
-        // Sanity check
-        let loader = format!("{}/{}", sysroot, executable.loader);
-        if !Path::new(&loader).exists() {
+    // Statically linked ones come first, they don't depend on the host's
+    // libraries
+    let mut candidates = qemu_candidates(executable);
+    candidates.sort_by_key(|qemu| !is_static(qemu));
+    let only_static = if options.static_qemu {
+        Some(true)
+    } else if options.no_static_qemu {
+        Some(false)
+    } else {
+        settings.static_qemu
+    };
+    if let Some(only_static) = only_static {
+        candidates.retain(|qemu| is_static(qemu) == only_static);
+        if candidates.is_empty() {
             panic!(
-                "{} does not exist, {} is not setup correctly.",
-                executable.loader, sysroot
+                "There is no {} linked qemu-{}, check --static-qemu, --no-static-qemu and the static-qemu setting in the config files.",
+                if only_static { "statically" } else { "dynamically" },
+                executable.target.qemu
             );
         }
+    }
 
-        Command::new(format!("/usr/bin/qemu-{}{}", qemu_suffix, static_suffix))
-            .arg("-R")
-            .arg("0xf7000000")
-            .arg("-cpu")
-            .arg("max")
-            .arg(format!("{}/{}", sysroot, &executable.loader))
-            .arg("--library-path")
-            .arg(format!(
-                "{root}/usr/lib{suffix}:{root}/lib{suffix}",
-                root = sysroot,
-                suffix = match executable.class {
-                    ELFClass::ELFCLASS64 => "64",
-                    _ => "",
-                }
-            ))
-            .args(&args[1..])
-            .status()
-            .unwrap_or_else(|_| {
-                panic!(
-                    "Unable to run /usr/bin/qemu-{}{} using {} as sysroot.",
-                    qemu_suffix, static_suffix, sysroot
-                )
-            });
-    } else {
-        // If there is no sysroot then the loader should exist in the filesystem.
-        // Check that and error otherwise.
+    choice::pick(
+        &format!("qemu-{}", executable.target.qemu),
+        &format!("qemu.{}", executable.target.qemu),
+        &candidates,
+        interactive(options),
+    )
+    .unwrap_or_else(|| {
+        panic!(
+            "qemu-{} not found, install qemu-user (apt: qemu-user, dnf: qemu-user, pacman: qemu-user, zypper: qemu-linux-user), pass --qemu or add its directory to QEMU_PATH.",
+            executable.target.qemu
+        )
+    })
+}
 
-        if !executable.loader.is_empty() && !Path::new(&executable.loader).exists() {
-            panic!("{}", format!("{} does not exist, consider setting EMU_SYSROOT variable to a working sysroot path.", executable.loader));
+// A static executable has no loader
+fn is_static(path: &str) -> bool {
+    File::open(path)
+        .and_then(|mut f| ElfInfo::parse(&mut f))
+        .is_ok_and(|elf| elf.interpreter.is_empty())
+}
+
+// Where qemu-user is installed besides PATH: source builds, and distributions
+// which keep it out of PATH
+static QEMU_DIRS: &[&str] = &[
+    "/usr/local/bin",
+    "/opt/qemu/bin",
+    "/usr/libexec",
+    "/usr/libexec/qemu",
+];
+
+// Installed qemu-<arch> binaries: QEMU_PATH (a colon separated list like
+// PATH), the distribution's, PATH and then the other usual places
+fn qemu_candidates(executable: &Executable) -> Vec<String> {
+    let name = format!("qemu-{}", executable.target.qemu);
+    // On Ubuntu executables are named as qemu-<arch>-static
+    let names = [format!("{}-static", name), name];
+
+    let qemu_path = env::var_os("QEMU_PATH").unwrap_or_default();
+    let path = env::var_os("PATH").unwrap_or_default();
+    let dirs = env::split_paths(&qemu_path)
+        .chain(std::iter::once(PathBuf::from("/usr/bin")))
+        .chain(env::split_paths(&path))
+        .chain(QEMU_DIRS.iter().map(PathBuf::from))
+        .filter(|dir| !dir.as_os_str().is_empty());
+
+    let mut seen = Vec::new();
+    let mut candidates = Vec::new();
+    for dir in dirs {
+        for name in &names {
+            let path = dir.join(name);
+            // The same binary can be reached through several PATH entries
+            match fs::canonicalize(&path) {
+                Ok(canonical) if path.is_file() && !seen.contains(&canonical) => {
+                    seen.push(canonical);
+                    candidates.push(path.to_string_lossy().to_string());
+                }
+                _ => {}
+            }
         }
+    }
+    if !candidates.is_empty() {
+        info!("found {}", candidates.join(", "));
+    }
+    candidates
+}
 
-        Command::new(format!("/usr/bin/qemu-{}{}", qemu_suffix, static_suffix))
-            .arg("-R")
-            .arg("0xf7000000")
-            .arg("-cpu")
-            .arg("max")
-            .args(&args[1..])
-            .status()
-            .unwrap_or_else(|_| {
-                panic!(
-                    "Unable to run /usr/bin/qemu-{}{}",
-                    qemu_suffix, static_suffix
-                )
-            });
+// Whether emu may ask which qemu or sysroot to use
+fn interactive(options: &RunArgs) -> bool {
+    !options.no_interactive && !options.quiet
+}
+
+// EMU_SYSROOT_<ARCH>, e.g. EMU_SYSROOT_AARCH64
+fn arch_sysroot(qemu: &str) -> Option<String> {
+    env::var(format!("EMU_SYSROOT_{}", qemu.to_uppercase())).ok()
+}
+
+// --sysroot, falling back to EMU_SYSROOT_<ARCH>, EMU_SYSROOT, the config
+// files and then the default from emu sysroot
+fn sysroot(options: &RunArgs, settings: &Settings, qemu: Option<&str>) -> String {
+    options
+        .sysroot
+        .clone()
+        .or_else(|| qemu.and_then(arch_sysroot))
+        .or_else(|| env::var("EMU_SYSROOT").ok())
+        .or_else(|| settings.sysroot.clone())
+        .map(|sysroot| sysroot::resolve(sysroot, qemu))
+        .or_else(|| qemu.and_then(sysroot::default_for))
+        .unwrap_or_default()
+}
+
+// proot runs the guest with the sysroot as / and passes every foreign
+// executable it starts to qemu, so the guest's loader and absolute paths are
+// the sysroot's. The executable and the working directory are bound in.
+fn proot_command(
+    executable: &Executable,
+    qemu: &str,
+    args: &[String],
+    options: &RunArgs,
+    settings: &Settings,
+    sysroot: &str,
+) -> Command {
+    if sysroot.is_empty() {
+        panic!("--backend proot needs a sysroot to use as the root, pass --sysroot or set EMU_SYSROOT.");
+    }
+    if !Path::new(sysroot).is_dir() {
+        panic!(
+            "{} is not a directory, check --sysroot, EMU_SYSROOT and the sysroot setting in the config files.",
+            sysroot
+        );
     }
+    if !options.dry_run && !foreign::in_path("proot") {
+        panic!("proot is not installed, it is needed for --backend proot.");
+    }
+    // -q is used for every executable the guest starts
+    if options.argv0.is_some() {
+        report::warning("proot can't set argv[0], --argv0 is ignored.");
+    }
+
+    // -q takes qemu and its options as one string split at spaces
+    let qemu_command = qemu_command(executable, qemu, options, settings);
+    let translator: Vec<String> = std::iter::once(qemu_command.get_program())
+        .chain(qemu_command.get_args())
+        .map(|arg| arg.to_string_lossy().to_string())
+        .collect();
+    if translator.iter().any(|arg| arg.contains(' ')) {
+        report::warning(
+            "proot splits the qemu command at spaces, options with spaces in them break.",
+        );
+    }
+
+    let mut command = Command::new("proot");
+    command
+        .arg("-R")
+        .arg(sysroot)
+        .arg("-q")
+        .arg(translator.join(" "));
+    if let Ok(cwd) = env::current_dir() {
+        command.arg("-b").arg(&cwd).arg("-w").arg(&cwd);
+    }
+    if let Some(dir) = executable
+        .path
+        .parent()
+        .and_then(|dir| fs::canonicalize(dir).ok())
+    {
+        command.arg("-b").arg(dir);
+    }
+    command.args(&args[1..]);
+    command
 }
 
-fn setup_executable(executable: &str) -> Result<Executable, io::Error> {
-    let mut f = File::open(&executable)?;
+// --loader-mode, then loader-mode from the config files. Only files qemu -L
+// finds in the sysroot are written to an overlay, gdb only finds the
+// executable's symbols and qemu -0 only reaches it when qemu runs it rather
+// than the loader.
+fn loader_mode(options: &RunArgs, settings: &Settings) -> LoaderMode {
+    if let Some(mode) = options.loader_mode {
+        return mode;
+    }
+    if options.overlay.is_some() || options.gdb.is_some() || options.argv0.is_some() {
+        return LoaderMode::Prefix;
+    }
+    match settings.loader_mode.as_deref() {
+        Some(mode) => LoaderMode::from_str(mode, true).unwrap_or_else(|_| {
+            panic!(
+                "{} is not a loader mode, loader-mode is explicit or prefix.",
+                mode
+            )
+        }),
+        None => LoaderMode::Explicit,
+    }
+}
 
-    // https://man7.org/linux/man-pages/man5/elf.5.html
-    //  #define EI_NIDENT 16
+// qemu -L finds the loader at the interpreter's path in the sysroot but
+// follows symlinks on the host, an absolute one leaves the sysroot.
+// Otherwise the loader has to be run explicitly.
+fn prefix_finds_loader(sysroot: &Path, interpreter: &str, loader: &Path) -> bool {
+    let found = fs::canonicalize(sysroot.join(interpreter.trim_start_matches('/')));
+    let finds = found.is_ok() && found.ok() == fs::canonicalize(loader).ok();
+    if !finds {
+        info!(
+            "qemu -L can't find {} in the sysroot by itself, running the loader",
+            interpreter
+        );
+    }
+    finds
+}
 
-    // typedef struct {
-    //      unsigned char e_ident[EI_NIDENT];
-    //      uint16_t      e_type;
-    //      uint16_t      e_machine;
-    //      uint32_t      e_version;
-    //      ElfN_Addr     e_entry; (uint32_t or uint64_t)
-    //      ElfN_Off      e_phoff; (uint32_t or uint64_t)
-    //      uint32_t      e_flags;
-    //      uint16_t      e_ehsize;
-    //      uint16_t      e_phentsize;
-    //      uint16_t      e_phnum;
-    //      uint16_t      e_shentsize;
-    //      uint16_t      e_shnum;
-    //      uint16_t      e_shstrndx;
-    // } ElfN_Ehdr;
+// --argv0, otherwise the name of the symlink the program was started through
+fn argv0(options: &RunArgs) -> Option<&String> {
+    options.argv0.as_ref().or(options.program_name.as_ref())
+}
 
-    let mut e_ident = [0; 16];
+// glibc 2.33 and musl loaders take --argv0, older ones would fail on it
+fn loader_takes_argv0(loader: &Path) -> bool {
+    fs::read(loader).is_ok_and(|data| data.windows(5).any(|window| window == b"argv0"))
+}
 
-    // Read the elf magic
-    f.read_exact(&mut e_ident)?;
-    if e_ident[..4] != ELF_MAGIC {
-        panic!("{} is not an ELF file.", executable);
+fn run_executable(
+    executable: Executable,
+    args: &[String],
+    options: &RunArgs,
+    settings: &Settings,
+    debugger: Option<Debugger>,
+) -> i32 {
+    // -cpu max covers everything TCG can do up to x86-64-v3, there is no
+    // AVX-512 emulation though.
+    if executable.x86_isa & GNU_PROPERTY_X86_ISA_1_V4 != 0 {
+        report::warning(&format!(
+            "{} requires x86-64-v4 (AVX-512) which qemu does not emulate, expect SIGILL.",
+            args[1]
+        ));
     }
 
-    // EI_CLASS
-    let exec_class = ELFClass::try_from(e_ident[4]).unwrap_or_else(|_| {
-        panic!("Invalid ELF class.");
-    });
+    let qemu = qemu_path(&executable, options, settings);
+    info!("using {}", qemu);
 
-    // EI_DATA
-    let exec_endian = Endian::try_from(e_ident[5]).unwrap_or_else(|_| {
-        panic!("Unknown endianness.");
-    });
+    // An Android sysroot can be kept apart from the Linux one, in
+    // EMU_ANDROID_SYSROOT or the android-sysroot setting
+    let android_sysroot = env::var("EMU_ANDROID_SYSROOT")
+        .ok()
+        .or_else(|| settings.android_sysroot.clone())
+        .filter(|_| is_bionic(&executable) && options.sysroot.is_none());
+    let sysroot = match android_sysroot {
+        Some(android_sysroot) => sysroot::resolve(android_sysroot, Some(executable.target.qemu)),
+        None => sysroot(options, settings, Some(executable.target.qemu)),
+    };
+    if !sysroot.is_empty() {
+        info!("sysroot {}", sysroot);
+    }
 
-    // Skip e_type
-    f.seek(SeekFrom::Current(2))?;
-    let mut e_machine = [0; 2];
-    f.read_exact(&mut e_machine)?;
+    // The sysroot's own settings come before the config files'
+    let mut pinned = Settings::default();
+    if Path::new(&sysroot).is_dir() {
+        pinned = sysroot::settings(Path::new(&sysroot));
+        if config::project_path().is_some() {
+            sysroot::check_drift(Path::new(&sysroot));
+        }
+    }
+    pinned.merge(settings);
+    let settings = &pinned;
+    let mut used = Vec::new();
+    if strace::log_path(options, &executable.path).is_some() {
+        used.push("--strace");
+    }
+    version::check(
+        &qemu,
+        executable.target.qemu,
+        &cpu(&executable, options, settings),
+        &used,
+    );
 
-    let machine_type_value: u16 = unpack!(e_machine, u16, &exec_endian);
-    let exec_machine = Machine::try_from(machine_type_value).unwrap_or_else(|_| {
-        panic!(
-            "{} is not a supported executable, machine type: {}",
-            executable, machine_type_value
+    // Unmounted when emu exits, after qemu or from the panic hook. A sysroot
+    // which isn't there is reported below.
+    let overlay = match &options.overlay {
+        Some(name)
+            if Path::new(&sysroot).is_dir()
+                && !executable.elf.interpreter.is_empty()
+                && !options.dry_run =>
+        {
+            Some(overlay::mount(Path::new(&sysroot), name.as_deref()))
+        }
+        _ => None,
+    };
+    let sysroot = match &overlay {
+        Some(overlay) => overlay.merged.to_string_lossy().to_string(),
+        None => sysroot,
+    };
+
+    // For --dry-run --format json
+    let mut loader_path = None;
+    let mut search_path = Vec::new();
+
+    // Statically linked executables have no loader and need nothing from
+    // the sysroot, they are run directly even when EMU_SYSROOT is set.
+    let (mut command, error) = if options.backend == Backend::Proot {
+        (
+            proot_command(&executable, &qemu, args, options, settings, &sysroot),
+            format!("Unable to run proot with {} as the root", sysroot),
         )
-    });
+    } else if (!sysroot.is_empty() || options.ld.is_some())
+        && !executable.elf.interpreter.is_empty()
+    {
+        //println!("Sysroot: {}, Loader: {}", sysroot, executable.elf.interpreter);
 
-    let pheader_offset: u64;
-    let pheader_size: u16;
-
-    match exec_class {
-        ELFClass::ELFCLASS32 => {
-            let mut e_phoff = [0; 4];
-            // Skip e_version + e_entry
-            f.seek(SeekFrom::Current(4 + 4))?;
-            f.read_exact(&mut e_phoff)?;
-            pheader_offset = unpack!(e_phoff, u32, &exec_endian).into();
-
-            let mut e_phentsize = [0; 2];
-            // Skip e_shoff + e_flags + e_ehsize
-            f.seek(SeekFrom::Current(4 + 4 + 2))?;
-            f.read_exact(&mut e_phentsize)?;
-            pheader_size = unpack!(e_phentsize, u16, &exec_endian);
-        }
-        ELFClass::ELFCLASS64 => {
-            let mut e_phoff = [0; 8];
-            // Skip e_version + e_entry
-            f.seek(SeekFrom::Current(4 + 8))?;
-            f.read_exact(&mut e_phoff)?;
-            pheader_offset = unpack!(e_phoff, u64, &exec_endian);
-
-            let mut e_phentsize = [0; 2];
-            // Skip e_shoff + e_flags + e_ehsize
-            f.seek(SeekFrom::Current(8 + 4 + 2))?;
-            f.read_exact(&mut e_phentsize)?;
-            pheader_size = unpack!(e_phentsize, u16, &exec_endian);
-        }
-    }
-
-    let ph_num: u16;
-    let mut e_phnum = [0; 2];
-    f.read_exact(&mut e_phnum)?;
-    ph_num = unpack!(e_phnum, u16, &exec_endian);
-
-    /*
-    typedef struct {
-        uint32_t   p_type;
-        Elf32_Off  p_offset;
-        Elf32_Addr p_vaddr;
-        Elf32_Addr p_paddr;
-        uint32_t   p_filesz;
-        uint32_t   p_memsz;
-        uint32_t   p_flags;
-        uint32_t   p_align;
-    } Elf32_Phdr;
-
-    typedef struct {
-        uint32_t   p_type;
-        uint32_t   p_flags;
-        Elf64_Off  p_offset;
-        Elf64_Addr p_vaddr;
-        Elf64_Addr p_paddr;
-        uint64_t   p_filesz;
-        uint64_t   p_memsz;
-        uint64_t   p_align;
-    } Elf64_Phdr;
-    */
-
-    f.seek(SeekFrom::Start(pheader_offset))?;
-    let mut i = 0;
-    let mut header_type: u32;
-    let mut p_type = [0; 4];
-    let mut load_address: u64 = 0;
-    let mut load_flags: u32;
-    let mut virtual_address: u64 = 0;
-    let mut interpreter_size: u64 = 0;
-    let mut exec_loader: String = String::new();
-
-    const PT_LOAD: u32 = 1;
-    const PT_INTERP: u32 = 3;
-
-    const PF_X: u32 = 1 << 0;
-    const PF_R: u32 = 1 << 2;
-    const PF_RX: u32 = PF_R | PF_X;
-
-    while i < ph_num {
-        f.read_exact(&mut p_type)?;
-
-        header_type = unpack!(p_type, u32, &exec_endian);
-
-        if header_type == PT_LOAD {
-            let mut p_flags = [0; 4];
-
-            match exec_class {
-                ELFClass::ELFCLASS32 => {
-                    let mut p_vaddr = [0; 4];
-                    // Skip p_offset
-                    f.seek(SeekFrom::Current(4))?;
-                    f.read_exact(&mut p_vaddr)?;
-                    let load_address_maybe: u32 = unpack!(p_vaddr, u32, &exec_endian);
-                    // Skip p_paddr + p_filesz + p_memsz
-                    f.seek(SeekFrom::Current(4 + 4 + 4))?;
-                    f.read_exact(&mut p_flags)?;
-                    load_flags = unpack!(p_flags, u32, &exec_endian);
-
-                    if load_flags == PF_R || load_flags == PF_RX {
-                        load_address = load_address_maybe.into();
-                        break;
-                    }
+        if !sysroot.is_empty() && !Path::new(&sysroot).is_dir() {
+            panic!(
+                "{} {}, check --sysroot, EMU_SYSROOT and the sysroot setting in the config files.",
+                sysroot,
+                if Path::new(&sysroot).exists() {
+                    "is not a directory"
+                } else {
+                    "does not exist"
                 }
-                ELFClass::ELFCLASS64 => {
-                    let mut p_vaddr = [0; 8];
-                    f.read_exact(&mut p_flags)?;
-                    load_flags = unpack!(p_flags, u32, &exec_endian);
-
-                    // Skip p_offset
-                    f.seek(SeekFrom::Current(8))?;
-                    f.read_exact(&mut p_vaddr)?;
-                    let load_address_maybe: u64 = unpack!(p_vaddr, u64, &exec_endian);
-
-                    if load_flags == PF_R || load_flags == PF_RX {
-                        load_address = load_address_maybe;
-                        break;
-                    }
+            );
+        }
+
+        // The config file can name a different loader for the architecture
+        let interpreter = settings
+            .loader
+            .as_deref()
+            .unwrap_or(&executable.elf.interpreter);
+
+        // --ld is used as it is, wherever it is
+        let loader = match &options.ld {
+            Some(ld) if Path::new(ld).is_file() => PathBuf::from(ld),
+            Some(ld) => panic!("{} does not exist, check --ld.", ld),
+            // Sanity check
+            None => find_in_sysroot(Path::new(&sysroot), Path::new(interpreter))
+                .or_else(|missing| {
+                    multiarch_loader(Path::new(&sysroot), &executable, interpreter).ok_or(missing)
+                })
+                .or_else(|missing| {
+                    discover::toolchain_loader(Path::new(&sysroot), interpreter).ok_or(missing)
+                })
+                .unwrap_or_else(|missing| {
+                let default_loader = default_loader(&executable);
+                if default_loader != interpreter
+                    && resolve_in_sysroot(Path::new(&sysroot), Path::new(default_loader)).is_some()
+                {
+                    panic!(
+                        "{} does not exist, {} provides {} instead. Is the sysroot built for a different libc or ABI?",
+                        interpreter, sysroot, default_loader
+                    );
                 }
+                panic!(
+                    "{} does not exist in {}, {} is missing. Is the sysroot complete?",
+                    interpreter,
+                    sysroot,
+                    missing.display()
+                );
+            }),
+        };
+
+        info!("loader {}", loader.display());
+        info!(
+            "library path {}",
+            library_path(&sysroot, &executable, settings)
+        );
+
+        check_loader(&loader, &executable);
+        if !sysroot.is_empty() {
+            check_library_dirs(Path::new(&sysroot), &executable, settings);
+            check_needed_libraries(Path::new(&sysroot), &executable, settings);
+        }
+
+        loader_path = Some(loader.to_string_lossy().to_string());
+
+        // A loader without --argv0 can't keep the name of a busybox style
+        // link, qemu -0 can when it runs the program itself
+        let renamed = options.argv0.is_none()
+            && options
+                .program_name
+                .as_ref()
+                .is_some_and(|name| Path::new(name).file_name() != Path::new(&args[1]).file_name());
+        let prefix = loader_mode(options, settings) == LoaderMode::Prefix
+            || (renamed
+                && options.loader_mode.is_none()
+                && !is_bionic(&executable)
+                && !loader_takes_argv0(&loader));
+
+        let mut command = qemu_command(&executable, &qemu, options, settings);
+        if prefix
+            && !sysroot.is_empty()
+            && prefix_finds_loader(Path::new(&sysroot), &executable.elf.interpreter, &loader)
+        {
+            // The loader searches the sysroot by itself, only the extra
+            // directories are passed, as guest paths
+            search_path = settings
+                .library_path
+                .iter()
+                .map(|dir| dir.strip_prefix('=').unwrap_or(dir).to_string())
+                .collect();
+
+            command.arg("-L").arg(&sysroot);
+            if let Some(argv0) = argv0(options) {
+                command.arg("-0").arg(argv0);
             }
-        } else if header_type == PT_INTERP {
-            match exec_class {
-                ELFClass::ELFCLASS32 => {
-                    let mut p_vaddr = [0; 4];
-                    // Skip p_offset
-                    f.seek(SeekFrom::Current(4))?;
-                    f.read_exact(&mut p_vaddr)?;
-                    virtual_address = unpack!(p_vaddr, u32, &exec_endian).into();
-
-                    let mut p_filesz = [0; 4];
-                    // Skip p_vaddr + p_paddr
-                    f.seek(SeekFrom::Current(4 + 4))?;
-                    f.read_exact(&mut p_filesz)?;
-                    interpreter_size = unpack!(p_filesz, u32, &exec_endian).into();
-                }
-                ELFClass::ELFCLASS64 => {
-                    let mut p_vaddr = [0; 8];
-                    // Skip p_flags + p_offset
-                    f.seek(SeekFrom::Current(4 + 8))?;
-                    f.read_exact(&mut p_vaddr)?;
-                    virtual_address = unpack!(p_vaddr, u64, &exec_endian);
-
-                    let mut p_filesz = [0; 8];
-                    // Skip p_paddr
-                    f.seek(SeekFrom::Current(8))?;
-                    f.read_exact(&mut p_filesz)?;
-                    interpreter_size = unpack!(p_filesz, u64, &exec_endian);
+            if !search_path.is_empty() {
+                command
+                    .arg("-E")
+                    .arg(format!("LD_LIBRARY_PATH={}", search_path.join(":")));
+            }
+        } else if is_bionic(&executable) {
+            search_path = library_search_path(&sysroot, &executable, settings);
+
+            // The bionic linker has no --library-path option
+            if options.argv0.is_some() {
+                report::warning("The bionic linker can't set argv[0], --argv0 is ignored.");
+            }
+            command
+                .arg("-E")
+                .arg(format!("LD_LIBRARY_PATH={}", search_path.join(":")))
+                .arg(&loader);
+        } else {
+            search_path = library_search_path(&sysroot, &executable, settings);
+
+            command
+                .arg(&loader)
+                .arg("--library-path")
+                .arg(search_path.join(":"));
+            // qemu -0 would only rename the loader
+            if let Some(argv0) = argv0(options) {
+                if loader_takes_argv0(&loader) {
+                    command.arg("--argv0").arg(argv0);
+                } else if options.argv0.is_some() {
+                    report::warning(&format!(
+                        "{} is too old to set argv[0], --argv0 is ignored.",
+                        loader.display()
+                    ));
+                } else if renamed {
+                    report::warning(&format!(
+                        "{} is too old to set argv[0], the program sees {} instead of {}.",
+                        loader.display(),
+                        args[1],
+                        argv0
+                    ));
                 }
             }
         }
+        command.args(&args[1..]);
+        (
+            command,
+            format!("Unable to run {} using {} as sysroot", qemu, sysroot),
+        )
+    } else {
+        // If there is no sysroot then the loader should exist in the filesystem.
+        // Check that and error otherwise.
+
+        if !executable.elf.interpreter.is_empty()
+            && !Path::new(&executable.elf.interpreter).exists()
+        {
+            panic!("{}", format!("{} does not exist, consider passing --sysroot or setting EMU_SYSROOT to a working sysroot path.", executable.elf.interpreter));
+        }
+
+        let mut command = qemu_command(&executable, &qemu, options, settings);
+        if let Some(argv0) = argv0(options) {
+            command.arg("-0").arg(argv0);
+        }
+        command.args(&args[1..]);
+        (command, format!("Unable to run {}", qemu))
+    };
+
+    environment::clean(&mut command, options, settings);
+
+    // emu debug starts the debugger along with qemu
+    let debugger = debugger.map(|debugger| {
+        debug::debugger_command(
+            debugger,
+            options.gdb.unwrap_or_default(),
+            &executable.path,
+            &sysroot,
+        )
+    });
+
+    if options.dry_run {
+        let mut details = json!({
+            "program": args[1],
+            "arch": executable.target.qemu,
+            "class": match executable.elf.class {
+                ELFClass::ELFCLASS32 => 32,
+                ELFClass::ELFCLASS64 => 64,
+            },
+            "endian": match executable.elf.endian {
+                Endian::Little => "little",
+                Endian::Big => "big",
+            },
+            "interpreter": (!executable.elf.interpreter.is_empty()).then_some(&executable.elf.interpreter),
+            "sysroot": (!sysroot.is_empty()).then_some(&sysroot),
+            "loader": loader_path,
+            "library_path": search_path,
+            "qemu": qemu,
+        });
+        if let Some(debugger) = &debugger {
+            let argv: Vec<String> = std::iter::once(debugger.get_program())
+                .chain(debugger.get_args())
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect();
+            details["debugger"] = argv.into();
+        }
+        shell::print(&command, options.format, details);
+        if let (Some(debugger), OutputFormat::Text) = (&debugger, options.format) {
+            println!("{}", shell::command_line(debugger));
+        }
+        return 0;
+    }
+
+    if let Some(debugger) = debugger {
+        info!("running {}", shell::command_line(&command));
+        let status = debug::run(command, debugger, options.gdb.unwrap_or_default());
+        return shell::exit_code(status);
+    }
+
+    if let Some(port) = options.gdb {
+        if !options.quiet {
+            debug::print_connect(port, &executable.path, &sysroot);
+        }
+    }
+
+    qemu_log::prepare(options, settings);
+    info!("running {}", shell::command_line(&command));
+    let start = Instant::now();
+    let status = command
+        .status()
+        .unwrap_or_else(|e| panic!("{} ({}).", error, e));
+    info!(
+        "{} finished ({}) after {:.3}s",
+        qemu,
+        status,
+        start.elapsed().as_secs_f64()
+    );
+    strace::finish(options, &executable.path);
+    if let Some(log) = qemu_log::path(options, settings, &executable.path) {
+        if !options.quiet {
+            eprintln!("qemu log written to {}", log.display());
+        }
+    }
+
+    shell::exit_code(status)
+}
 
-        i += 1;
-        f.seek(SeekFrom::Start(pheader_offset + (i * pheader_size) as u64))?;
+// Why the executable couldn't be read, the InvalidData errors of the ELF
+// parser read on from the name
+fn executable_error(executable: &str, e: io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::NotFound => format!("{} does not exist.", executable),
+        io::ErrorKind::PermissionDenied => format!("{} is not readable.", executable),
+        io::ErrorKind::IsADirectory => format!("{} is a directory.", executable),
+        io::ErrorKind::UnexpectedEof => format!("{} is truncated.", executable),
+        io::ErrorKind::InvalidData => format!("{} {}.", executable, e),
+        _ => format!("Unable to read {} ({}).", executable, e),
     }
+}
+
+fn setup_executable(executable: &str, options: &RunArgs) -> Result<Executable, io::Error> {
+    let mut f = File::open(executable)?;
 
-    if interpreter_size != 0 {
-        // interpreter is null terminated
-        interpreter_size -= 1;
+    let elf = ElfInfo::parse(&mut f)?;
 
-        f.seek(SeekFrom::Start(virtual_address - load_address))?;
-        let mut interpreter: Vec<u8> = Vec::with_capacity(interpreter_size as usize);
-        f.take(interpreter_size).read_to_end(&mut interpreter)?;
+    //println!("Loader: {}", elf.interpreter);
 
-        exec_loader = str::from_utf8(&interpreter).unwrap().to_string();
+    // EI_OSABI, checked before the machine so the error names the real problem
+    if let Some((_, os)) = FOREIGN_OSABIS.iter().find(|(osabi, _)| *osabi == elf.osabi) {
+        panic!(
+            "{} is a {}/{} executable, only Linux executables can be run.",
+            executable,
+            os,
+            arch::machine_name(elf.machine)
+                .map(String::from)
+                .unwrap_or_else(|| format!("machine {}", elf.machine))
+        );
+    }
+
+    match elf.elf_type {
+        ET_REL => {
+            if elf.section(&mut f, ".modinfo")?.is_some() {
+                panic!(
+                    "{} is a kernel module, load it with insmod inside a guest kernel instead.",
+                    executable
+                );
+            }
+            panic!(
+                "{} is a relocatable object file, it needs to be linked first.",
+                executable
+            );
+        }
+        ET_CORE => panic!("{} is a core dump, open it with gdb instead.", executable),
+        ET_EXEC | ET_DYN => {}
+        _ => panic!(
+            "{} is not an executable (e_type {:#x}).",
+            executable, elf.elf_type
+        ),
+    }
+
+    // Loaders and some libraries (ld.so, libc.so.6) can be run directly
+    if elf.is_shared_library() && !options.allow_shared {
+        panic!(
+            "{} is a shared library, pass --allow-shared to run it anyway.",
+            executable
+        );
     }
 
-    //println!("Loader: {}", exec_loader);
+    let mut forced = None;
+    let detected = arch::find_target(elf.machine, elf.class, elf.endian, elf.flags);
+
+    // --arch wins over the header, which may be wrong or not specific enough
+    if let Some(arch) = &options.arch {
+        let name = arch::qemu_name(arch)
+            .unwrap_or_else(|| panic!("{} is not an architecture qemu can emulate.", arch));
+        if let Some(detected) = detected.filter(|detected| detected.qemu != name) {
+            report::warning(&format!(
+                "{} is built for {}, running it as {} anyway.",
+                executable, detected.qemu, name
+            ));
+        }
+        forced = arch::find_by_name(name, elf.class, elf.endian, elf.flags);
+    }
+
+    let target = forced
+        .or(detected)
+        .unwrap_or_else(|| match arch::machine_name(elf.machine) {
+            Some(name) => panic!(
+                "{} is not a supported {} executable ({}-bit, {:?} endian, flags {:#x}).",
+                executable,
+                name,
+                match elf.class {
+                    ELFClass::ELFCLASS32 => 32,
+                    ELFClass::ELFCLASS64 => 64,
+                },
+                elf.endian,
+                elf.flags
+            ),
+            None => panic!(
+                "{} is not a supported executable, machine type: {}",
+                executable, elf.machine
+            ),
+        });
+
+    let attributes = match elf.machine {
+        EM_ARM => elf.build_attributes(
+            &mut f,
+            ".ARM.attributes",
+            "aeabi",
+            &[TAG_ARM_CPU_RAW_NAME, TAG_ARM_CPU_NAME, TAG_ARM_CONFORMANCE],
+        )?,
+        EM_RISCV => {
+            elf.build_attributes(&mut f, ".riscv.attributes", "riscv", &[TAG_RISCV_ARCH])?
+        }
+        _ => Vec::new(),
+    };
+
+    let float_abi = match elf.machine {
+        EM_ARM => arm_float_abi(elf.flags, &attributes),
+        _ => None,
+    };
+    let arm_arch = match elf.machine {
+        EM_ARM => arm_cpu_arch(&attributes),
+        _ => None,
+    };
+    let riscv_arch = match elf.machine {
+        EM_RISCV => riscv_arch(&attributes),
+        _ => None,
+    };
+
+    let x86_isa = match elf.machine {
+        EM_386 | EM_X86_64 => match elf.section(&mut f, ".note.gnu.property")? {
+            Some(data) => x86_isa_needed(&data, &elf.class, &elf.endian),
+            None => 0,
+        },
+        _ => 0,
+    };
+
+    info!(
+        "{}: {} ({}-bit, {:?} endian, flags {:#x}){}",
+        executable,
+        target.qemu,
+        match elf.class {
+            ELFClass::ELFCLASS32 => 32,
+            ELFClass::ELFCLASS64 => 64,
+        },
+        elf.endian,
+        elf.flags,
+        if elf.interpreter.is_empty() {
+            String::from(", statically linked")
+        } else {
+            format!(", interpreter {}", elf.interpreter)
+        }
+    );
+    if let Some(float_abi) = &float_abi {
+        debug!("float ABI {:?}", float_abi);
+    }
+    if let Some(arch) = arm_arch {
+        debug!("Tag_CPU_arch {}", arch);
+    }
+    if let Some(arch) = &riscv_arch {
+        debug!("Tag_RISCV_arch {}", arch);
+    }
+    if x86_isa != 0 {
+        debug!("x86 ISA needed {:#x}", x86_isa);
+    }
 
     let exec = Executable {
-        class: exec_class,
-        endian: exec_endian,
-        loader: exec_loader,
-        machine: exec_machine,
+        path: PathBuf::from(executable),
+        elf,
+        float_abi,
+        arm_arch,
+        riscv_arch,
+        x86_isa,
+        target,
     };
 
     Ok(exec)
 }
 
+// Where programs are looked up inside a sysroot
+static SYSROOT_PATH: &[&str] = &["/usr/local/bin", "/usr/bin", "/bin", "/usr/sbin", "/sbin"];
+
+// Find the program the way a shell would, through the sysroot and then
+// PATH, and resolve its symlinks. Symlinks inside a sysroot are resolved
+// relative to it so busybox style links (/bin/ls -> /bin/busybox) work.
+fn resolve_program(program: &str, sysroot: &str) -> String {
+    let path = Path::new(program);
+
+    let resolved = if !sysroot.is_empty() && path.starts_with(sysroot) {
+        resolve_in_sysroot(Path::new(sysroot), path.strip_prefix(sysroot).unwrap())
+    } else if path.exists() || program.contains('/') {
+        fs::canonicalize(path).ok()
+    } else {
+        // A bare name is most likely meant to come from the sysroot
+        let in_sysroot = || {
+            SYSROOT_PATH.iter().find_map(|dir| {
+                resolve_in_sysroot(Path::new(sysroot), &Path::new(dir).join(program))
+            })
+        };
+        let in_path = || {
+            env::split_paths(&env::var_os("PATH").unwrap_or_default())
+                .map(|dir| dir.join(program))
+                .find(|candidate| candidate.is_file())
+                .and_then(|candidate| fs::canonicalize(candidate).ok())
+        };
+        if sysroot.is_empty() {
+            in_path()
+        } else {
+            in_sysroot().or_else(in_path)
+        }
+    };
+
+    match resolved {
+        Some(resolved) => {
+            let resolved = resolved.to_string_lossy().to_string();
+            if resolved != program {
+                info!("{} resolved to {}", program, resolved);
+            }
+            resolved
+        }
+        // Let the ELF parser report it
+        None => program.to_string(),
+    }
+}
+
+// Interpreter and optional argument of a #! script. Like Linux everything
+// after the interpreter is passed as a single argument.
+fn read_shebang(path: &str) -> Result<Option<(String, Option<String>)>, io::Error> {
+    let mut line = Vec::with_capacity(256);
+    File::open(path)?.take(256).read_to_end(&mut line)?;
+
+    if !line.starts_with(b"#!") {
+        return Ok(None);
+    }
+    let line = String::from_utf8_lossy(&line[2..]);
+    let line = line.lines().next().unwrap_or_default().trim();
+
+    let (interpreter, argument) = match line.split_once([' ', '\t']) {
+        Some((interpreter, argument)) => (interpreter, Some(argument.trim().to_string())),
+        None => (line, None),
+    };
+    if interpreter.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some((interpreter.to_string(), argument)))
+}
+
+// Turn "script args" into "interpreter [argument] script args" with the
+// interpreter resolved in the sysroot.
+fn expand_shebang(args: &mut Vec<String>, sysroot: &str) {
+    let (interpreter, mut argument) = match read_shebang(&args[1]) {
+        Ok(Some(shebang)) => shebang,
+        _ => return,
+    };
+
+    let resolve = |path: &Path| -> Option<PathBuf> {
+        if sysroot.is_empty() {
+            path.exists().then(|| path.to_path_buf())
+        } else {
+            resolve_in_sysroot(Path::new(sysroot), path)
+        }
+    };
+
+    // #!/usr/bin/env python3 would look up python3 in the host PATH from
+    // inside qemu, look it up in the sysroot instead.
+    let mut program = PathBuf::from(&interpreter);
+    if Path::new(&interpreter).file_name() == Some("env".as_ref()) {
+        if let Some(name) = argument
+            .as_deref()
+            .filter(|name| !name.starts_with('-') && !name.contains(' '))
+        {
+            if let Some(path) = SYSROOT_PATH
+                .iter()
+                .map(|dir| Path::new(dir).join(name))
+                .find(|path| resolve(path).is_some())
+            {
+                program = path;
+                argument = None;
+            }
+        }
+    }
+
+    let resolved = resolve(&program).unwrap_or_else(|| {
+        if sysroot.is_empty() {
+            panic!(
+                "{} uses {} as interpreter which does not exist, consider passing --sysroot or setting EMU_SYSROOT to a working sysroot path.",
+                args[1],
+                program.display()
+            );
+        }
+        panic!(
+            "{} uses {} as interpreter which does not exist in {}.",
+            args[1],
+            program.display(),
+            sysroot
+        );
+    });
+
+    let mut interpreter_args = vec![resolved.to_string_lossy().to_string()];
+    interpreter_args.extend(argument);
+    info!("{} is run with {}", args[1], interpreter_args.join(" "));
+    args.splice(1..1, interpreter_args);
+}
+
+// No executable is this large, a stream which is must be something else
+const SPOOL_LIMIT: u64 = 1 << 30;
+
+// qemu reads the executable itself after emu has inspected it, which doesn't
+// work for a pipe. Copy the stream to a private file first.
+fn spool_to_temp(source: &str) -> Result<PathBuf, io::Error> {
+    let input: Box<dyn Read> = if source == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(source)?)
+    };
+
+    let mut attempt = 0;
+    loop {
+        let path = env::temp_dir().join(format!("emu-{}-{}", process::id(), attempt));
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o700)
+            .open(&path)
+        {
+            Ok(mut output) => {
+                report::remove_on_exit(path.clone());
+                if io::copy(&mut input.take(SPOOL_LIMIT + 1), &mut output)? > SPOOL_LIMIT {
+                    return Err(io::Error::other("it is larger than 1G"));
+                }
+                return Ok(path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// The <arch> of an emu-<arch> symlink, other names (emu-dev) don't count
+fn multicall_arch() -> Option<String> {
+    let argv0 = PathBuf::from(env::args_os().next()?);
+    let arch = argv0.file_name()?.to_str()?.strip_prefix("emu-")?;
+    arch::qemu_name(arch).map(|_| arch.to_string())
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let cli = cli::Cli::parse();
+    let format = match &cli.command {
+        Some(cli::Command::Run(run)) | Some(cli::Command::Strace(run)) => run.format,
+        Some(cli::Command::Debug(debug)) => debug.run.format,
+        None => cli.run.format,
+        _ => OutputFormat::Text,
+    };
+    let quiet = match &cli.command {
+        Some(cli::Command::Run(run)) | Some(cli::Command::Strace(run)) => run.quiet,
+        Some(cli::Command::Debug(debug)) => debug.run.quiet,
+        None => cli.run.quiet,
+        _ => false,
+    };
+    report::init(format, quiet);
 
-    if args.len() < 2 {
-        println!("Usage: {} program <args>", args[0]);
-        return;
+    let mut debugger = None;
+    let mut run = match cli.command {
+        Some(cli::Command::Config(command)) => return config::command(command),
+        Some(cli::Command::Sysroot(command)) => return sysroot::command(command),
+        Some(cli::Command::Completions { shell }) => {
+            return cli::print_completions(shell, config::load().profile.into_keys().collect())
+        }
+        Some(cli::Command::Run(run)) => run,
+        Some(cli::Command::Strace(mut run)) => {
+            run.strace = run.strace.or_else(|| Some(Vec::new()));
+            run
+        }
+        Some(cli::Command::Debug(mut debug)) => {
+            debugger = Some(debug.debugger);
+            debug.run.gdb = debug.run.gdb.or_else(|| Some(debug::free_port()));
+            debug.run
+        }
+        None => cli.run,
+    };
+    logging::init(run.verbose, run.quiet);
+
+    // Installed as emu-<arch> everything is run as <arch>, --arch still wins
+    if run.arch.is_none() {
+        run.arch = multicall_arch();
+        if let Some(arch) = &run.arch {
+            info!("running as {} because of the program name", arch);
+        }
     }
 
-    let executable = setup_executable(&args[1]).unwrap();
-    run_executable(executable, &args);
+    // Evaluating a flake takes a while even when nothing has to be built,
+    // the store path is only looked up once
+    if let Some(installable) = run.sysroot.as_deref().and_then(|s| s.strip_prefix("nix:")) {
+        run.sysroot = Some(nix::realize(installable));
+    }
+
+    let config = config::load();
+    let profile = run.profile.clone().or_else(|| env::var("EMU_PROFILE").ok());
+    if let Some(profile) = &profile {
+        info!("using profile {}", profile);
+    }
+
+    // Until the executable has been read only --arch says which [arch]
+    // section applies
+    let qemu = run.arch.as_deref().and_then(arch::qemu_name);
+    let settings = config.settings(qemu, profile.as_deref());
+
+    // The executable is args[1] from here on, like in the kernel's argv
+    let mut args: Vec<String> = vec![env::args().next().unwrap_or_default()];
+    args.extend(run.command.iter().cloned());
+
+    // "-" is stdin, /dev/fd/N and named pipes are streams as well. Other
+    // devices aren't executables, /dev/zero would never end.
+    let metadata = fs::metadata(&args[1]).ok();
+    let stream = args[1] == "-"
+        || ["/dev/stdin", "/dev/fd/", "/proc/self/fd/"]
+            .iter()
+            .any(|prefix| args[1].starts_with(prefix))
+        || metadata.as_ref().is_some_and(|metadata| {
+            metadata.file_type().is_fifo() || metadata.file_type().is_socket()
+        });
+    if !stream
+        && metadata.as_ref().is_some_and(|metadata| {
+            metadata.file_type().is_char_device() || metadata.file_type().is_block_device()
+        })
+    {
+        panic!("{} is a device, not an executable.", args[1]);
+    }
+    let spooled = if stream {
+        let path = spool_to_temp(&args[1])
+            .unwrap_or_else(|e| panic!("Unable to copy {} to a temporary file: {}", args[1], e));
+        debug!("copied {} to {}", args[1], path.display());
+        args[1] = path.to_string_lossy().to_string();
+        Some(path)
+    } else {
+        None
+    };
+
+    // The resolved file is run, the guest still sees the name it was
+    // started as
+    if spooled.is_none() {
+        let resolved = resolve_program(&args[1], &sysroot(&run, &settings, qemu));
+        if resolved != args[1] {
+            run.program_name = Some(args[1].clone());
+        }
+        args[1] = resolved;
+    }
+
+    if let Some(code) = foreign::run(&args, run.dry_run.then_some(run.format)) {
+        report::clean_up();
+        process::exit(code);
+    }
+
+    // A script's name is its interpreter's argument, not argv[0]
+    let script = args[1].clone();
+    expand_shebang(&mut args, &sysroot(&run, &settings, qemu));
+    if args[1] != script {
+        run.program_name = None;
+    }
+
+    let executable = setup_executable(&args[1], &run)
+        .unwrap_or_else(|e| panic!("{}", executable_error(&args[1], e)));
+    let mut settings = config.settings(Some(executable.target.qemu), profile.as_deref());
+
+    // The config files can each name a sysroot for the architecture, an
+    // explicit one or a profile's doesn't leave a choice.
+    let profile_sysroot = profile
+        .as_ref()
+        .and_then(|profile| config.profile.get(profile))
+        .is_some_and(|profile| profile.sysroot.is_some());
+    if run.sysroot.is_none()
+        && arch_sysroot(executable.target.qemu).is_none()
+        && env::var_os("EMU_SYSROOT").is_none()
+        && !profile_sysroot
+    {
+        let mut candidates: Vec<String> = config::sysroot_candidates(executable.target.qemu)
+            .into_iter()
+            .filter(|sysroot| Path::new(sysroot).is_dir())
+            .collect();
+        // Otherwise the Buildroot tree the executable is in, and without a
+        // registered sysroot the SDKs on the machine
+        if candidates.is_empty() {
+            if let Some(target) = discover::buildroot_target(&executable.path) {
+                candidates.push(target.to_string_lossy().to_string());
+            } else if sysroot::default_for(executable.target.qemu).is_none() {
+                candidates = discover::sysroots(executable.target.qemu);
+            }
+        }
+        if let Some(sysroot) = choice::pick(
+            &format!("sysroot for {}", executable.target.qemu),
+            &format!("sysroot.{}", executable.target.qemu),
+            &candidates,
+            interactive(&run),
+        ) {
+            settings.sysroot = Some(sysroot);
+        }
+    }
+
+    // -L directories come last, relative ones are relative to the working
+    // directory and =dir is inside the sysroot like for ld
+    let cwd = env::current_dir().unwrap_or_default();
+    settings
+        .library_path
+        .extend(run.library_path.iter().map(|dir| {
+            if dir.starts_with('=') {
+                dir.clone()
+            } else {
+                cwd.join(dir).to_string_lossy().to_string()
+            }
+        }));
+
+    let code = run_executable(executable, &args, &run, &settings, debugger);
+
+    report::clean_up();
+    process::exit(code);
 }
@@ -0,0 +1,46 @@
+// Sysroots from the Nix store, --sysroot nix:<installable>. Store paths are
+// the same inside and outside a sysroot, so they are never looked up in it:
+// Nix executables name their loader and libraries by store path.
+
+use std::path::Path;
+use std::process::Command;
+
+use crate::fetch;
+
+pub fn is_store_path(path: &Path) -> bool {
+    path.starts_with("/nix/store")
+}
+
+// The store path of a flake output or package, built or fetched if needed:
+// nix:.#pkgsCross.aarch64-multiplatform.stdenv.cc.libc or nix:/nix/store/...
+pub fn realize(installable: &str) -> String {
+    if Path::new(installable).is_dir() {
+        return installable.to_string();
+    }
+
+    let output = Command::new("nix")
+        .args(["--extra-experimental-features", "nix-command flakes"])
+        .args(["build", "--no-link", "--print-out-paths", installable])
+        .stderr(fetch::stderr())
+        .output()
+        .unwrap_or_else(|e| panic!("Unable to run nix: {}, is it installed?", e));
+    if !output.status.success() {
+        panic!(
+            "nix build {} failed ({}).{}",
+            installable,
+            output.status,
+            fetch::failure(&output.stderr)
+        );
+    }
+
+    // libc has outputs for headers and binaries as well, the sysroot is the
+    // one with the libraries
+    let paths = String::from_utf8_lossy(&output.stdout);
+    let paths: Vec<&str> = paths.lines().collect();
+    paths
+        .iter()
+        .find(|path| Path::new(path).join("lib").is_dir())
+        .or(paths.first())
+        .map(|path| path.to_string())
+        .unwrap_or_else(|| panic!("nix build {} didn't print a store path.", installable))
+}
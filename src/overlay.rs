@@ -0,0 +1,112 @@
+// --overlay, a writable overlayfs over the sysroot. Root mounts the kernel's
+// overlayfs, users need fuse-overlayfs.
+
+use log::info;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use crate::bootstrap;
+use crate::fetch;
+use crate::foreign;
+use crate::report;
+use crate::sysroot;
+
+pub struct Overlay {
+    pub merged: PathBuf,
+}
+
+// Named overlays are kept in $XDG_DATA_HOME/emu/overlays/<name>, each for
+// the sysroot it was first used with. The overlay is unmounted when emu
+// exits, errors included.
+pub fn mount(lower: &Path, name: Option<&str>) -> Overlay {
+    let lower = fs::canonicalize(lower)
+        .unwrap_or_else(|e| panic!("Unable to use {} as a sysroot: {}", lower.display(), e));
+    let (dir, temporary) = match name {
+        Some(name) => (
+            sysroot::data_dir()
+                .expect(
+                    "Neither XDG_DATA_HOME nor HOME is set, there is nowhere to keep the overlay.",
+                )
+                .join("overlays")
+                .join(name),
+            false,
+        ),
+        None => (
+            env::temp_dir().join(format!("emu-overlay-{}", process::id())),
+            true,
+        ),
+    };
+
+    let lower_file = dir.join("lower");
+    match fs::read_to_string(&lower_file) {
+        Ok(used) if Path::new(used.trim()) != lower => panic!(
+            "The overlay {} is for {}, not {}. Use another name or delete {}.",
+            name.unwrap_or_default(),
+            used.trim(),
+            lower.display(),
+            dir.display()
+        ),
+        _ => {}
+    }
+
+    let upper = dir.join("upper");
+    let work = dir.join("work");
+    let merged = dir.join("merged");
+    for dir in [&upper, &work, &merged] {
+        fs::create_dir_all(dir)
+            .unwrap_or_else(|e| panic!("Unable to create {}: {}", dir.display(), e));
+    }
+    fs::write(&lower_file, lower.to_string_lossy().as_bytes())
+        .unwrap_or_else(|e| panic!("Unable to write {}: {}", lower_file.display(), e));
+
+    let options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower.display(),
+        upper.display(),
+        work.display()
+    );
+    let mut command = if bootstrap::is_root() {
+        let mut mount = Command::new("mount");
+        mount.args(["-t", "overlay", "overlay"]);
+        mount
+    } else if foreign::in_path("fuse-overlayfs") {
+        Command::new("fuse-overlayfs")
+    } else {
+        panic!("fuse-overlayfs is not installed, it is needed for --overlay as a user.");
+    };
+    command.arg("-o").arg(options).arg(&merged);
+    fetch::run(command);
+    info!("overlay {} on {}", merged.display(), lower.display());
+
+    let mounted = merged.clone();
+    report::on_exit(move || unmount(&mounted, &dir, temporary));
+    Overlay { merged }
+}
+
+// Per-run overlays are deleted once unmounted
+fn unmount(merged: &Path, dir: &Path, temporary: bool) {
+    let mut command = if bootstrap::is_root() {
+        Command::new("umount")
+    } else if foreign::in_path("fusermount3") {
+        let mut fusermount = Command::new("fusermount3");
+        fusermount.arg("-u");
+        fusermount
+    } else {
+        let mut fusermount = Command::new("fusermount");
+        fusermount.arg("-u");
+        fusermount
+    };
+    let unmounted = command
+        .arg(merged)
+        .stderr(fetch::stderr())
+        .output()
+        .is_ok_and(|output| output.status.success());
+
+    // Never delete through a mount that is still there
+    if temporary && unmounted {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,230 @@
+// Minimal sysroots assembled from binary packages for emu sysroot from-debs.
+// Only the data.tar of each package is unpacked and maintainer scripts never
+// run, so neither root nor a chroot is needed.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::arch;
+use crate::fetch;
+use crate::report;
+use crate::sysroot;
+use crate::sysroot::Origin;
+
+struct Package {
+    filename: String,
+    sha256: String,
+    // Each entry is a list of alternatives (a | b)
+    depends: Vec<Vec<String>>,
+}
+
+// libc6 (>= 2.36), libgcc-s1 | libgcc1, python3:any
+fn parse_depends(value: &str) -> Vec<Vec<String>> {
+    value
+        .split(',')
+        .map(|dependency| {
+            dependency
+                .split('|')
+                .filter_map(|alternative| {
+                    let name = alternative.split_whitespace().next()?;
+                    let name = name.split(['(', ':']).next().unwrap_or(name);
+                    (!name.is_empty()).then(|| name.to_string())
+                })
+                .collect()
+        })
+        .filter(|alternatives: &Vec<String>| !alternatives.is_empty())
+        .collect()
+}
+
+// Packages by name, and the packages providing each virtual package
+fn parse_index(index: &str) -> (BTreeMap<String, Package>, BTreeMap<String, String>) {
+    let mut packages = BTreeMap::new();
+    let mut provides = BTreeMap::new();
+
+    for stanza in index.split("\n\n") {
+        let mut name = None;
+        let mut package = Package {
+            filename: String::new(),
+            sha256: String::new(),
+            depends: Vec::new(),
+        };
+        let mut provided = Vec::new();
+
+        // Continuation lines (Description) start with a space and are skipped
+        for line in stanza.lines().filter(|line| !line.starts_with(' ')) {
+            let (key, value) = match line.split_once(": ") {
+                Some(field) => field,
+                None => continue,
+            };
+            match key {
+                "Package" => name = Some(value.to_string()),
+                "Filename" => package.filename = value.to_string(),
+                "SHA256" => package.sha256 = value.to_string(),
+                "Depends" | "Pre-Depends" => package.depends.extend(parse_depends(value)),
+                "Provides" => provided.extend(parse_depends(value).into_iter().flatten()),
+                _ => {}
+            }
+        }
+
+        if let Some(name) = name {
+            for virtual_name in provided {
+                provides.entry(virtual_name).or_insert_with(|| name.clone());
+            }
+            packages.insert(name, package);
+        }
+    }
+    (packages, provides)
+}
+
+// names and everything they depend on, the first installable alternative of
+// each dependency is used
+fn resolve<'a>(
+    names: &[String],
+    packages: &'a BTreeMap<String, Package>,
+    provides: &BTreeMap<String, String>,
+    with_dependencies: bool,
+) -> Vec<&'a Package> {
+    let lookup = |name: &str| {
+        packages
+            .get_key_value(name)
+            .or_else(|| packages.get_key_value(provides.get(name)?))
+    };
+
+    let mut seen = BTreeSet::new();
+    let mut pending: VecDeque<&str> = names.iter().map(String::as_str).collect();
+    let mut resolved = Vec::new();
+    while let Some(name) = pending.pop_front() {
+        let (name, package) = lookup(name)
+            .unwrap_or_else(|| panic!("There is no package named {} in the archive.", name));
+        if !seen.insert(name.as_str()) {
+            continue;
+        }
+        resolved.push(package);
+
+        if !with_dependencies {
+            continue;
+        }
+        for alternatives in &package.depends {
+            match alternatives.iter().find(|name| lookup(name).is_some()) {
+                Some(dependency) => pending.push_back(dependency),
+                None => report::warning(&format!(
+                    "skipping {}, a dependency of {} which isn't in the archive.",
+                    alternatives.join(" | "),
+                    name
+                )),
+            }
+        }
+    }
+    resolved
+}
+
+// .deb and .ipk files are ar archives (old .ipk ones tar.gz) with the files
+// in data.tar.*
+fn unpack(package: &Path, dir: &Path) {
+    let work = dir.join(".emu-unpack");
+    fs::create_dir_all(&work)
+        .unwrap_or_else(|e| panic!("Unable to create {}: {}", work.display(), e));
+
+    let ar = Command::new("ar")
+        .arg("x")
+        .arg(package)
+        .current_dir(&work)
+        .status();
+    if !ar.is_ok_and(|status| status.success()) {
+        let mut tar = Command::new("tar");
+        tar.arg("-xzf").arg(package).arg("-C").arg(&work);
+        fetch::run(tar);
+    }
+
+    let data = fs::read_dir(&work)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .find(|path| {
+            path.file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with("data.tar"))
+        })
+        .unwrap_or_else(|| panic!("{} has no data.tar in it.", package.display()));
+    fetch::extract(&data, dir);
+
+    let _ = fs::remove_dir_all(&work);
+}
+
+pub fn from_debs(
+    packages: &[String],
+    arch: &str,
+    release: Option<String>,
+    mirror: Option<String>,
+    with_dependencies: bool,
+    name: Option<String>,
+) {
+    let qemu = arch::qemu_name(arch)
+        .unwrap_or_else(|| panic!("{} is not an architecture qemu can emulate.", arch));
+    let release = release.unwrap_or_else(|| String::from("trixie"));
+    let mirror = mirror.unwrap_or_else(|| String::from("http://deb.debian.org/debian"));
+    let mirror = mirror.trim_end_matches('/');
+
+    let name = name.unwrap_or_else(|| format!("debs-{}-{}", release, qemu));
+    let dir = fetch::sysroot_dir(&name);
+
+    // Package files given by path are used as they are, names come from the
+    // archive
+    let (files, names): (Vec<String>, Vec<String>) = packages
+        .iter()
+        .cloned()
+        .partition(|package| package.ends_with(".deb") || package.ends_with(".ipk"));
+
+    let mut downloads = Vec::new();
+    if !names.is_empty() {
+        let debian_arch = fetch::debian_arch(qemu)
+            .unwrap_or_else(|| panic!("Debian has no packages for {}.", qemu));
+
+        // The index changes with every archive update, it is downloaded again
+        let index_url = format!(
+            "{}/dists/{}/main/binary-{}/Packages.xz",
+            mirror, release, debian_arch
+        );
+        let index_path = fetch::cache_dir()
+            .join("downloads")
+            .join(format!("Packages-{}-{}.xz", release, debian_arch));
+        fetch::download_to(&index_url, &index_path);
+        let output = Command::new("xz")
+            .arg("-dc")
+            .arg(&index_path)
+            .output()
+            .unwrap_or_else(|e| panic!("Unable to run xz: {}, is it installed?", e));
+        if !output.status.success() {
+            panic!("{} is not a valid package index.", index_url);
+        }
+        let index = String::from_utf8_lossy(&output.stdout);
+
+        let (packages, provides) = parse_index(&index);
+        for package in resolve(&names, &packages, &provides, with_dependencies) {
+            let sha256 = Some(package.sha256.as_str()).filter(|sha256| !sha256.is_empty());
+            downloads.push(fetch::download(
+                &format!("{}/{}", mirror, package.filename),
+                sha256,
+            ));
+        }
+    }
+
+    fetch::install(&dir, |partial| {
+        for file in files {
+            let file = fs::canonicalize(&file)
+                .unwrap_or_else(|e| panic!("Unable to read {}: {}", file, e));
+            unpack(&file, partial);
+        }
+        for download in &downloads {
+            unpack(download, partial);
+        }
+    });
+
+    let origin = Origin {
+        source: format!("{} {} ({})", mirror, release, packages.join(" ")),
+        fetch: None,
+    };
+    sysroot::register(&name, &dir, Some(qemu), Some(origin));
+    println!("Added {} as {}.", dir.display(), name);
+}
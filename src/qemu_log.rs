@@ -0,0 +1,111 @@
+// --qemu-log, qemu's -d debug logging. Every run writes its own file in the
+// log directory, the oldest ones are deleted before a run once the directory
+// is larger than qemu-log-size.
+
+use log::info;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::choice;
+use crate::cli::RunArgs;
+use crate::config::Settings;
+
+const DEFAULT_SIZE: &str = "1G";
+
+static STARTED: OnceLock<u64> = OnceLock::new();
+
+// --qemu-log-dir, qemu-log-dir from the config files, otherwise
+// $XDG_STATE_HOME/emu/qemu-logs
+fn dir(options: &RunArgs, settings: &Settings) -> PathBuf {
+    options
+        .qemu_log_dir
+        .clone()
+        .or_else(|| settings.qemu_log_dir.clone())
+        .map(PathBuf::from)
+        .or_else(|| choice::state_dir().map(|dir| dir.join("qemu-logs")))
+        .expect("Neither XDG_STATE_HOME nor HOME is set, pass --qemu-log-dir.")
+}
+
+// 512M, 2G or a number of bytes
+fn limit(settings: &Settings) -> u64 {
+    let size = settings.qemu_log_size.as_deref().unwrap_or(DEFAULT_SIZE);
+    let (number, unit) = match size.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((index, _)) => size.split_at(index),
+        None => (size, ""),
+    };
+    let unit = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => 0,
+    };
+    match number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+    {
+        Some(limit) if unit != 0 => limit,
+        _ => panic!("{} is not a size, qemu-log-size is like 512M or 2G.", size),
+    }
+}
+
+// <program>-<time>-<pid>.log, the same for every call in a run
+pub fn path(options: &RunArgs, settings: &Settings, program: &Path) -> Option<PathBuf> {
+    options.qemu_log.as_ref()?;
+    let started = STARTED.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default()
+    });
+    Some(dir(options, settings).join(format!(
+        "{}-{}-{}.log",
+        program.file_name().unwrap_or_default().to_string_lossy(),
+        started,
+        process::id()
+    )))
+}
+
+// Make room for the run's log, oldest first
+pub fn prepare(options: &RunArgs, settings: &Settings) {
+    if options.qemu_log.is_none() {
+        return;
+    }
+    let dir = dir(options, settings);
+    fs::create_dir_all(&dir)
+        .unwrap_or_else(|e| panic!("Unable to create {}: {}", dir.display(), e));
+
+    let mut logs: Vec<(SystemTime, u64, PathBuf)> = fs::read_dir(&dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "log"))
+        .filter_map(|path| {
+            let metadata = fs::metadata(&path).ok()?;
+            Some((metadata.modified().ok()?, metadata.len(), path))
+        })
+        .collect();
+    logs.sort();
+
+    let limit = limit(settings);
+    let mut total: u64 = logs.iter().map(|(_, size, _)| size).sum();
+    for (_, size, path) in logs {
+        if total <= limit {
+            break;
+        }
+        info!(
+            "removing {}, the qemu logs are over {} bytes",
+            path.display(),
+            limit
+        );
+        if fs::remove_file(&path).is_ok() {
+            total -= size;
+        }
+    }
+}
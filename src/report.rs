@@ -0,0 +1,98 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::io::IsTerminal;
+use std::panic;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Mutex, OnceLock};
+
+use crate::cli::OutputFormat;
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+static QUIET: OnceLock<bool> = OnceLock::new();
+static CLEANUPS: Mutex<Vec<Box<dyn FnOnce() + Send>>> = Mutex::new(Vec::new());
+
+// https://no-color.org, and only when a person is looking at stderr
+fn color(code: &str, text: &str) -> String {
+    if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) || !io::stderr().is_terminal()
+    {
+        return text.to_string();
+    }
+    format!("\x1b[{}m{}\x1b[0m", code, text)
+}
+
+fn report(level: &str, code: &str, message: &str) {
+    match FORMAT.get() {
+        Some(OutputFormat::Json) => {
+            let mut object = serde_json::Map::new();
+            object.insert(level.to_string(), message.into());
+            eprintln!("{}", serde_json::Value::Object(object));
+        }
+        _ => eprintln!("{} {}", color(code, &format!("{}:", level)), message),
+    }
+}
+
+pub fn quiet() -> bool {
+    QUIET.get() == Some(&true)
+}
+
+// --quiet leaves stderr to the guest, errors are still reported
+pub fn warning(message: &str) {
+    if quiet() {
+        return;
+    }
+    report("warning", "1;33", message);
+}
+
+// Temporary files and mounts are cleaned up however emu exits, errors exit
+// from the panic hook without unwinding
+pub fn on_exit(cleanup: impl FnOnce() + Send + 'static) {
+    if let Ok(mut cleanups) = CLEANUPS.lock() {
+        cleanups.push(Box::new(cleanup));
+    }
+}
+
+pub fn remove_on_exit(path: PathBuf) {
+    on_exit(move || {
+        let _ = fs::remove_file(path);
+    });
+}
+
+// Newest first, so a mount is gone before the directory it is in
+pub fn clean_up() {
+    let cleanups = match CLEANUPS.lock() {
+        Ok(mut cleanups) => std::mem::take(&mut *cleanups),
+        Err(_) => return,
+    };
+    for cleanup in cleanups.into_iter().rev() {
+        cleanup();
+    }
+}
+
+// Errors are reported by panicking, print them without the panic boilerplate.
+// RUST_BACKTRACE keeps the default output for debugging emu itself.
+pub fn init(format: OutputFormat, quiet: bool) {
+    let _ = FORMAT.set(format);
+    let _ = QUIET.set(quiet);
+    if env::var_os("RUST_BACKTRACE").is_some() {
+        let default = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            default(info);
+            clean_up();
+        }));
+        return;
+    }
+
+    panic::set_hook(Box::new(|info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .or_else(|| payload.downcast_ref::<&str>().copied())
+            .unwrap_or("unknown error");
+        report("error", "1;31", message);
+        clean_up();
+        process::exit(1);
+    }));
+}
@@ -0,0 +1,90 @@
+use std::ffi::OsStr;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus};
+
+use crate::cli::OutputFormat;
+
+// Single quotes keep everything literal except ' itself, which has to end
+// the quoted string and be escaped.
+pub fn quote(arg: &OsStr) -> String {
+    let arg = arg.to_string_lossy();
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c))
+    {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+// command as it would be typed into sh, environment changes included
+pub fn command_line(command: &Command) -> String {
+    let mut words = Vec::new();
+
+    let removed: Vec<&OsStr> = command
+        .get_envs()
+        .filter(|(_, value)| value.is_none())
+        .map(|(name, _)| name)
+        .collect();
+    if !removed.is_empty() {
+        words.push(String::from("env"));
+        for name in removed {
+            words.push(String::from("-u"));
+            words.push(quote(name));
+        }
+    }
+
+    for (name, value) in command.get_envs() {
+        if let Some(value) = value {
+            words.push(format!("{}={}", name.to_string_lossy(), quote(value)));
+        }
+    }
+
+    words.push(quote(command.get_program()));
+    words.extend(command.get_args().map(quote));
+    words.join(" ")
+}
+
+// --dry-run output, JSON adds the command's argv and environment changes to
+// details (removed variables are null).
+pub fn print(command: &Command, format: OutputFormat, details: serde_json::Value) {
+    match format {
+        OutputFormat::Text => println!("{}", command_line(command)),
+        OutputFormat::Json => {
+            let mut document = match details {
+                serde_json::Value::Object(document) => document,
+                _ => serde_json::Map::new(),
+            };
+
+            let argv: Vec<String> = std::iter::once(command.get_program())
+                .chain(command.get_args())
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect();
+            let env: serde_json::Map<String, serde_json::Value> = command
+                .get_envs()
+                .map(|(name, value)| {
+                    (
+                        name.to_string_lossy().to_string(),
+                        value.map_or(serde_json::Value::Null, |value| {
+                            value.to_string_lossy().into()
+                        }),
+                    )
+                })
+                .collect();
+
+            document.insert(String::from("argv"), argv.into());
+            document.insert(String::from("env"), env.into());
+            println!("{}", serde_json::Value::Object(document));
+        }
+    }
+}
+
+// The status emu exits with, like a shell 128 + the signal when the command
+// was killed by one
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
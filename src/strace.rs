@@ -0,0 +1,104 @@
+// --strace, qemu's -strace written to a file instead of the guest's stderr.
+// qemu can't trace only some syscalls, with a list the trace is written to a
+// temporary file and filtered after the run.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::cli::RunArgs;
+
+// --strace-output, "-" is stderr, otherwise <program>.strace in the working
+// directory
+fn output(options: &RunArgs, program: &Path) -> Option<PathBuf> {
+    match options.strace_output.as_deref() {
+        Some("-") => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(PathBuf::from(format!(
+            "{}.strace",
+            program.file_name().unwrap_or_default().to_string_lossy()
+        ))),
+    }
+}
+
+fn syscalls(options: &RunArgs) -> &[String] {
+    options.strace.as_deref().unwrap_or_default()
+}
+
+// Where qemu writes the trace with -D, None leaves it on stderr or in the
+// --qemu-log file. qemu has a single log, so the trace in it can't be
+// filtered or written elsewhere.
+pub fn log_path(options: &RunArgs, program: &Path) -> Option<PathBuf> {
+    options.strace.as_ref()?;
+    if options.qemu_log.is_some() {
+        if !syscalls(options).is_empty() || options.strace_output.is_some() {
+            panic!("With --qemu-log the trace goes into the qemu log, a list of syscalls or --strace-output can't be used with it.");
+        }
+        return None;
+    }
+    if syscalls(options).is_empty() {
+        output(options, program)
+    } else {
+        Some(env::temp_dir().join(format!("emu-strace-{}", process::id())))
+    }
+}
+
+// "1234 openat(AT_FDCWD,...) = 3" is openat
+fn syscall(line: &str) -> Option<&str> {
+    line.split_once(' ')
+        .filter(|(pid, _)| !pid.is_empty() && pid.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|(_, rest)| rest.split_once('('))
+        .map(|(name, _)| name)
+        .filter(|name| name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_'))
+}
+
+// The result of a call which blocked comes on its own " = 6" line and goes
+// with the call, signals and exits are always kept
+fn filter(trace: &str, syscalls: &[String]) -> String {
+    let mut keep = true;
+    let mut filtered = String::new();
+    for line in trace.lines() {
+        if let Some(name) = syscall(line) {
+            keep = syscalls.iter().any(|syscall| syscall == name);
+        } else if !line.starts_with(' ') {
+            keep = true;
+        }
+        if keep {
+            filtered.push_str(line);
+            filtered.push('\n');
+        }
+    }
+    filtered
+}
+
+// After qemu exited, filter the trace into --strace-output and say where it
+// is
+pub fn finish(options: &RunArgs, program: &Path) {
+    let log = match log_path(options, program) {
+        Some(log) => log,
+        None => return,
+    };
+    let output = output(options, program);
+
+    let syscalls = syscalls(options);
+    if !syscalls.is_empty() {
+        let trace = fs::read_to_string(&log).unwrap_or_default();
+        let _ = fs::remove_file(&log);
+        let filtered = filter(&trace, syscalls);
+        match &output {
+            Some(path) => fs::write(path, filtered)
+                .unwrap_or_else(|e| panic!("Unable to write {}: {}", path.display(), e)),
+            None => {
+                let _ = std::io::stderr().write_all(filtered.as_bytes());
+            }
+        }
+    }
+
+    if let Some(path) = output {
+        if !options.quiet {
+            eprintln!("strace written to {}", path.display());
+        }
+    }
+}
@@ -0,0 +1,636 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::arch;
+use crate::archive;
+use crate::bootstrap;
+use crate::choice;
+use crate::cli::SysrootCommand;
+use crate::config::Settings;
+use crate::discover;
+use crate::elf::ElfInfo;
+use crate::fetch;
+use crate::nix;
+use crate::packages;
+use crate::report;
+
+// $XDG_DATA_HOME/emu/sysroots.toml, written by emu sysroot:
+//
+//   [default]
+//   aarch64 = "bookworm"
+//
+//   [sysroot.bookworm]
+//   path = "/srv/bookworm-arm64"
+//   arch = "aarch64"
+//   hash = "3a7bd3e2..."
+//   cpu = "cortex-a72"
+#[derive(Default, Deserialize, Serialize)]
+#[serde(default)]
+struct Registry {
+    // qemu name to sysroot name
+    default: BTreeMap<String, String>,
+    sysroot: BTreeMap<String, Entry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    path: String,
+    // qemu name
+    arch: String,
+    // sha256 of the library directories when it was registered, see fingerprint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    origin: Option<Origin>,
+    // cpu, qemu-args, env and the rest, like .emu-sysroot.toml
+    #[serde(flatten)]
+    settings: Settings,
+}
+
+// Where a sysroot made by emu sysroot came from
+#[derive(Deserialize, Serialize)]
+pub struct Origin {
+    // URL, container image, packages or distribution release
+    pub source: String,
+    // Source, release and libc of emu sysroot fetch, to look for updates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fetch: Option<[String; 3]>,
+}
+
+// $XDG_DATA_HOME/emu, falling back to ~/.local/share/emu
+pub fn data_dir() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share"))
+        })?;
+    Some(dir.join("emu"))
+}
+
+fn registry_path() -> PathBuf {
+    data_dir()
+        .expect("Neither XDG_DATA_HOME nor HOME is set, there is no sysroot list.")
+        .join("sysroots.toml")
+}
+
+fn load() -> Registry {
+    let path = match data_dir() {
+        Some(dir) => dir.join("sysroots.toml"),
+        None => return Registry::default(),
+    };
+    match fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents)
+            .unwrap_or_else(|e| panic!("{} is not a valid sysroot list: {}", path.display(), e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Registry::default(),
+        Err(e) => panic!("Unable to read {}: {}", path.display(), e),
+    }
+}
+
+fn save(registry: &Registry) {
+    let path = registry_path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .unwrap_or_else(|e| panic!("Unable to create {}: {}", dir.display(), e));
+    }
+    let contents = toml::to_string(registry).expect("Unable to serialize the sysroot list");
+    fs::write(&path, contents)
+        .unwrap_or_else(|e| panic!("Unable to write {}: {}", path.display(), e));
+}
+
+// Architecture (qemu name) and libc of a sysroot, read from the first loader
+// found in it.
+pub fn detect(sysroot: &Path) -> Option<(&'static str, &'static str)> {
+    let mut loaders: Vec<PathBuf> = arch::TARGETS
+        .iter()
+        .map(|target| PathBuf::from(target.loader))
+        .collect();
+    for pattern in [
+        "/lib/ld-musl-*.so.1",
+        "/lib/ld-uClibc*",
+        "/system/bin/linker64",
+        "/system/bin/linker",
+    ] {
+        loaders.extend(crate::glob_in_sysroot(sysroot, Path::new(pattern)));
+    }
+    // Debian without the /lib compatibility symlinks
+    for dir in ["/lib", "/usr/lib"] {
+        for multiarch in crate::glob_in_sysroot(sysroot, &Path::new(dir).join("*-linux-gnu*")) {
+            loaders.extend(crate::glob_in_sysroot(sysroot, &multiarch.join("ld*")));
+        }
+    }
+
+    loaders.iter().find_map(|loader| {
+        let path = crate::resolve_in_sysroot(sysroot, loader)?;
+        let elf = File::open(path)
+            .and_then(|mut f| ElfInfo::parse(&mut f))
+            .ok()?;
+        let target = arch::find_target(elf.machine, elf.class, elf.endian, elf.flags)?;
+
+        let name = loader.file_name()?.to_str()?;
+        let libc = if name.starts_with("ld-musl") {
+            "musl"
+        } else if name.starts_with("ld-uClibc") {
+            "uClibc"
+        } else if name.starts_with("linker") {
+            "bionic"
+        } else {
+            "glibc"
+        };
+        Some((target.qemu, libc))
+    })
+}
+
+// --sysroot and the sysroot setting take a registered name as well as a
+// path, a directory of that name wins. An SDK directory stands for its
+// sysroot for qemu, nix: ones are built into the store and images are
+// unpacked or mounted.
+pub fn resolve(sysroot: String, qemu: Option<&str>) -> String {
+    if let Some(installable) = sysroot.strip_prefix("nix:") {
+        return nix::realize(installable);
+    }
+    let sysroot = if sysroot.contains('/') || Path::new(&sysroot).exists() {
+        sysroot
+    } else {
+        match load().sysroot.get(&sysroot) {
+            Some(entry) => entry.path.clone(),
+            None => sysroot,
+        }
+    };
+    if archive::is_archive(Path::new(&sysroot)) {
+        return archive::prepare(Path::new(&sysroot))
+            .to_string_lossy()
+            .to_string();
+    }
+    match discover::expand(Path::new(&sysroot), qemu) {
+        Some(expanded) => expanded.to_string_lossy().to_string(),
+        None => sysroot,
+    }
+}
+
+// $XDG_CACHE_HOME/emu/file-hashes.toml, the sha256 of every file fingerprint
+// read, per sysroot:
+//
+//   ["/srv/bookworm-arm64"."lib/aarch64-linux-gnu/libc.so.6"]
+//   stat = "1922320 1718000000 0 1718000100 0 2752513"
+//   sha256 = "9f86d0..."
+#[derive(Clone, Deserialize, PartialEq, Serialize)]
+struct FileHash {
+    stat: String,
+    sha256: String,
+}
+
+type FileHashes = BTreeMap<String, BTreeMap<String, FileHash>>;
+
+fn file_hashes_path() -> Option<PathBuf> {
+    fetch::cache_home().map(|dir| dir.join("file-hashes.toml"))
+}
+
+// Size, mtime, ctime and inode: the change time and inode also change when a
+// file is replaced by one with the same size and mtime, as rsync and tar do
+fn stat(metadata: &fs::Metadata) -> String {
+    format!(
+        "{} {} {} {} {} {}",
+        metadata.size(),
+        metadata.mtime(),
+        metadata.mtime_nsec(),
+        metadata.ctime(),
+        metadata.ctime_nsec(),
+        metadata.ino()
+    )
+}
+
+// sha256sum of several files in one run, files which can't be read hash to
+// an empty string
+fn sha256_files(paths: &[PathBuf]) -> Vec<String> {
+    let mut hashes = Vec::new();
+    for chunk in paths.chunks(256) {
+        let output = Command::new("sha256sum")
+            .arg("--")
+            .args(chunk)
+            .stderr(Stdio::null())
+            .output()
+            .unwrap_or_else(|e| panic!("Unable to run sha256sum: {}, is it installed?", e));
+        // "<sha256>  <file>", a \ in front when the name had to be escaped
+        let lines: Vec<String> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .map(|hash| hash.trim_start_matches('\\').to_string())
+            .collect();
+        if lines.len() == chunk.len() {
+            hashes.extend(lines);
+        } else {
+            hashes.extend(chunk.iter().map(|path| {
+                sha256_files(std::slice::from_ref(path))
+                    .pop()
+                    .unwrap_or_default()
+            }));
+        }
+    }
+    hashes
+}
+
+fn sha256(input: &[u8]) -> String {
+    let mut command = Command::new("sha256sum")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| panic!("Unable to run sha256sum: {}, is it installed?", e));
+    if let Some(mut stdin) = command.stdin.take() {
+        let _ = stdin.write_all(input);
+    }
+    let output = command
+        .wait_with_output()
+        .unwrap_or_else(|e| panic!("Unable to run sha256sum: {}", e));
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+// The files of a sysroot's library directories, which is what emu and the
+// loader use from it, relative to the sysroot. An image is a file of its own.
+fn library_files(sysroot: &Path) -> Vec<PathBuf> {
+    if sysroot.is_file() {
+        return vec![PathBuf::new()];
+    }
+
+    let mut dirs: Vec<PathBuf> = ["lib", "lib32", "lib64", "usr/lib", "usr/lib32", "usr/lib64"]
+        .iter()
+        .map(|dir| sysroot.join(dir))
+        .collect();
+    for dir in ["/lib", "/usr/lib"] {
+        dirs.extend(crate::glob_in_sysroot(
+            sysroot,
+            &Path::new(dir).join("*-linux-*"),
+        ));
+    }
+
+    let mut files = Vec::new();
+    for dir in dirs {
+        let mut entries: Vec<PathBuf> = fs::read_dir(&dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let is_dir = entry.file_type().ok()?.is_dir();
+                (!is_dir).then(|| entry.path())
+            })
+            .map(|path| path.strip_prefix(sysroot).unwrap_or(&path).to_path_buf())
+            .collect();
+        entries.sort();
+        files.extend(entries);
+    }
+    files
+}
+
+// The sha256 of the contents of a sysroot's library directories (symlinks by
+// their target), or of an image. Each file is only read again once its stat
+// changed, so this is cheap enough for every run.
+pub fn fingerprint(sysroot: &Path) -> String {
+    let key = sysroot.to_string_lossy().to_string();
+    let cache_path = file_hashes_path();
+    let mut cache: FileHashes = cache_path
+        .as_ref()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|contents| toml::from_str(&contents).ok())
+        .unwrap_or_default();
+    let known = cache.remove(&key).unwrap_or_default();
+
+    let mut hashes = BTreeMap::new();
+    let mut stale = Vec::new();
+    // Name to sha256, or to -> target for symlinks
+    let mut manifest = BTreeMap::new();
+    for file in library_files(sysroot) {
+        let path = if file.as_os_str().is_empty() {
+            sysroot.to_path_buf()
+        } else {
+            sysroot.join(&file)
+        };
+        let name = file.to_string_lossy().to_string();
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(&path).unwrap_or_default();
+            manifest.insert(name, format!("-> {}", target.display()));
+            continue;
+        }
+        let hash = FileHash {
+            stat: stat(&metadata),
+            sha256: String::new(),
+        };
+        match known.get(&name).filter(|known| known.stat == hash.stat) {
+            Some(known) => {
+                hashes.insert(name, known.clone());
+            }
+            None => stale.push((name, path, hash)),
+        }
+    }
+
+    let paths: Vec<PathBuf> = stale.iter().map(|(_, path, _)| path.clone()).collect();
+    for ((name, _, mut hash), sha256) in stale.into_iter().zip(sha256_files(&paths)) {
+        hash.sha256 = sha256;
+        hashes.insert(name, hash);
+    }
+    for (name, hash) in &hashes {
+        manifest.insert(name.clone(), hash.sha256.clone());
+    }
+    let manifest: String = manifest
+        .iter()
+        .map(|(name, hash)| format!("{} {}\n", name, hash))
+        .collect();
+
+    if let Some(path) = cache_path.filter(|_| hashes != known) {
+        cache.insert(key, hashes);
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        if let Ok(contents) = toml::to_string(&cache) {
+            let _ = fs::write(path, contents);
+        }
+    }
+    sha256(manifest.as_bytes())
+}
+
+// Warn when the sysroot a project runs with isn't what it was the last time
+pub fn check_drift(sysroot: &Path) {
+    let key = format!("fingerprint.{}", sysroot.display());
+    let hash = fingerprint(sysroot);
+    match choice::remembered(&key) {
+        Some(previous) if previous == hash => return,
+        Some(_) => report::warning(&format!(
+            "{} changed since this project last used it, results may differ from before.",
+            sysroot.display()
+        )),
+        None => {}
+    }
+    choice::remember(&key, &hash);
+}
+
+// Settings pinned for a sysroot by the .emu-sysroot.toml in it and its entry
+// in the sysroot list, which wins. The file's library paths are inside the
+// sysroot. The sysroot and qemu are already chosen when these are read.
+pub fn settings(sysroot: &Path) -> Settings {
+    let path = sysroot.join(".emu-sysroot.toml");
+    let mut settings: Settings = match fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents).unwrap_or_else(|e| {
+            panic!(
+                "{} is not a valid sysroot settings file: {}",
+                path.display(),
+                e
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Settings::default(),
+        Err(e) => panic!("Unable to read {}: {}", path.display(), e),
+    };
+    for dir in &mut settings.library_path {
+        if !dir.starts_with('=') {
+            *dir = format!("={}", Path::new("/").join(&*dir).display());
+        }
+    }
+
+    // A registered image is used through the directory it was unpacked or
+    // mounted on
+    let sysroot = fs::canonicalize(sysroot).unwrap_or_else(|_| sysroot.to_path_buf());
+    for entry in load().sysroot.values() {
+        let path = Path::new(&entry.path);
+        if path == sysroot
+            || (archive::is_archive(path)
+                && archive::prepared_dir(path).is_some_and(|dir| dir == sysroot))
+        {
+            settings.merge(&entry.settings);
+        }
+    }
+
+    settings.sysroot = None;
+    settings.android_sysroot = None;
+    settings.qemu = None;
+    settings
+}
+
+pub fn is_registered(name: &str) -> bool {
+    load().sysroot.contains_key(name)
+}
+
+// The sysroot registered as the default for a qemu name
+pub fn default_for(qemu: &str) -> Option<String> {
+    let registry = load();
+    let name = registry.default.get(qemu)?;
+    let path = &registry.sysroot.get(name)?.path;
+    if archive::is_archive(Path::new(path)) {
+        return Some(
+            archive::prepare(Path::new(path))
+                .to_string_lossy()
+                .to_string(),
+        );
+    }
+    Some(path.clone())
+}
+
+// Register path as name, the first sysroot of an architecture becomes its
+// default.
+pub fn register(name: &str, path: &Path, arch: Option<&str>, origin: Option<Origin>) {
+    let path = fs::canonicalize(path)
+        .unwrap_or_else(|e| panic!("Unable to use {} as a sysroot: {}", path.display(), e));
+    // Images are unpacked or mounted to see what's in them
+    let dir = if archive::is_archive(&path) {
+        archive::prepare(&path)
+    } else if path.is_dir() {
+        path.clone()
+    } else {
+        panic!(
+            "{} is neither a directory nor a tarball or squashfs image.",
+            path.display()
+        );
+    };
+
+    let arch = match arch {
+        Some(arch) => arch::qemu_name(arch)
+            .unwrap_or_else(|| panic!("{} is not an architecture qemu can emulate.", arch)),
+        None => detect(&dir).map(|(arch, _)| arch).unwrap_or_else(|| {
+            panic!(
+                "Can't tell the architecture of {}, it has no known loader. Pass --arch.",
+                path.display()
+            )
+        }),
+    };
+
+    let mut registry = load();
+    if registry.sysroot.contains_key(name) {
+        panic!(
+            "There already is a sysroot named {}, remove it first.",
+            name
+        );
+    }
+    registry.sysroot.insert(
+        name.to_string(),
+        Entry {
+            path: path.to_string_lossy().to_string(),
+            arch: arch.to_string(),
+            hash: Some(fingerprint(&path)),
+            origin,
+            settings: Settings::default(),
+        },
+    );
+    registry
+        .default
+        .entry(arch.to_string())
+        .or_insert_with(|| name.to_string());
+    save(&registry);
+}
+
+fn list() {
+    let registry = load();
+    let rows: Vec<[String; 4]> = registry
+        .sysroot
+        .iter()
+        .map(|(name, entry)| {
+            let path = Path::new(&entry.path);
+            let libc = match detect(path) {
+                Some((_, libc)) => libc,
+                None if path.is_dir() => "unknown",
+                None if path.is_file() => "image",
+                None => "missing",
+            };
+            let default = registry.default.get(&entry.arch) == Some(name);
+            [
+                format!("{}{}", name, if default { " *" } else { "" }),
+                entry.arch.clone(),
+                libc.to_string(),
+                entry.path.clone(),
+            ]
+        })
+        .collect();
+
+    let widths: Vec<usize> = (0..3)
+        .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+    for row in rows {
+        println!(
+            "{:name$}  {:arch$}  {:libc$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            name = widths[0],
+            arch = widths[1],
+            libc = widths[2]
+        );
+    }
+}
+
+fn check(name: Option<&str>) {
+    let registry = load();
+    if let Some(name) = name.filter(|name| !registry.sysroot.contains_key(*name)) {
+        panic!(
+            "{} is not a registered sysroot, see emu sysroot list.",
+            name
+        );
+    }
+
+    for (entry_name, entry) in &registry.sysroot {
+        if name.is_some_and(|name| name != entry_name) {
+            continue;
+        }
+        let path = Path::new(&entry.path);
+        if !path.exists() {
+            println!("{}: {} is missing", entry_name, entry.path);
+            continue;
+        }
+        match &entry.hash {
+            Some(hash) if *hash != fingerprint(path) => {
+                println!("{}: changed since it was registered", entry_name)
+            }
+            Some(_) => println!("{}: unchanged", entry_name),
+            None => println!("{}: registered without a hash", entry_name),
+        }
+
+        let origin = match &entry.origin {
+            Some(origin) => origin,
+            None => continue,
+        };
+        if let Some(latest) = origin
+            .fetch
+            .as_ref()
+            .and_then(|fetched| fetch::latest_url(fetched, &entry.arch))
+            .filter(|latest| *latest != origin.source)
+        {
+            println!("{}: update available, {}", entry_name, latest);
+        }
+    }
+}
+
+fn remove(name: &str) {
+    let mut registry = load();
+    if registry.sysroot.remove(name).is_none() {
+        panic!(
+            "{} is not a registered sysroot, see emu sysroot list.",
+            name
+        );
+    }
+    registry.default.retain(|_, default| default != name);
+    save(&registry);
+}
+
+fn set_default(name: &str) {
+    let mut registry = load();
+    let arch = match registry.sysroot.get(name) {
+        Some(entry) => entry.arch.clone(),
+        None => panic!(
+            "{} is not a registered sysroot, see emu sysroot list.",
+            name
+        ),
+    };
+    registry.default.insert(arch, name.to_string());
+    save(&registry);
+}
+
+pub fn command(command: SysrootCommand) {
+    match command {
+        SysrootCommand::Add { arch, name, path } => {
+            register(&name, Path::new(&path), arch.as_deref(), None)
+        }
+        SysrootCommand::Fetch {
+            source,
+            arch,
+            release,
+            libc,
+            name,
+        } => fetch::fetch(source, &arch, release, libc, name),
+        SysrootCommand::FromImage { arch, name, image } => {
+            fetch::from_image(&image, arch.as_deref(), name)
+        }
+        SysrootCommand::FromDebs {
+            arch,
+            release,
+            mirror,
+            no_deps,
+            name,
+            packages,
+        } => packages::from_debs(&packages, &arch, release, mirror, !no_deps, name),
+        SysrootCommand::Create {
+            distro,
+            arch,
+            release,
+            mirror,
+            include,
+            name,
+        } => bootstrap::create(distro, &arch, release, mirror, &include, name),
+        SysrootCommand::List => list(),
+        SysrootCommand::Check { name } => check(name.as_deref()),
+        SysrootCommand::Remove { name } => remove(&name),
+        SysrootCommand::Default { name } => set_default(&name),
+    }
+}
@@ -0,0 +1,137 @@
+// qemu releases that added what emu asks for. An old distribution qemu
+// otherwise fails with an unknown cpu property or doesn't exist at all.
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::process::Command;
+
+use crate::fetch;
+
+// Targets new enough to need a recent qemu
+static TARGETS: &[(&str, (u32, u32))] = &[("loongarch64", (7, 1))];
+
+// riscv cpu properties emu turns on from the executable's attributes
+static CPU_PROPERTIES: &[(&str, (u32, u32))] = &[
+    ("v", (7, 0)),
+    ("zvbb", (8, 2)),
+    ("zvbc", (8, 2)),
+    ("zvkb", (8, 2)),
+    ("zvkg", (8, 2)),
+    ("zvkned", (8, 2)),
+    ("zvknha", (8, 2)),
+    ("zvknhb", (8, 2)),
+    ("zvksed", (8, 2)),
+    ("zvksh", (8, 2)),
+];
+
+// emu options which rely on a newer qemu, -strace only writes to -D's file
+// since 5.1
+static OPTIONS: &[(&str, (u32, u32))] = &[("--strace", (5, 1))];
+
+// $XDG_CACHE_HOME/emu/qemu-versions.toml, qemu is only asked again when the
+// binary changed:
+//
+//   ["/usr/bin/qemu-riscv64"]
+//   size = 5724296
+//   mtime = 1718000000
+//   version = "8.2.2"
+#[derive(Deserialize, Serialize)]
+struct Cached {
+    size: u64,
+    mtime: i64,
+    version: String,
+}
+
+// "qemu-riscv64 version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)" -> 8.2.2
+fn parse(output: &str) -> Option<String> {
+    let mut words = output.split_whitespace();
+    words.find(|word| *word == "version")?;
+    words.next().map(String::from)
+}
+
+fn version(qemu: &str) -> Option<String> {
+    let metadata = fs::metadata(qemu).ok()?;
+    // Without a cache directory qemu is asked every time
+    let path = fetch::cache_home().map(|dir| dir.join("qemu-versions.toml"));
+    let mut cache: BTreeMap<String, Cached> = path
+        .as_ref()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|contents| toml::from_str(&contents).ok())
+        .unwrap_or_default();
+    if let Some(cached) = cache.get(qemu) {
+        if cached.size == metadata.size() && cached.mtime == metadata.mtime() {
+            return Some(cached.version.clone());
+        }
+    }
+
+    let output = Command::new(qemu).arg("--version").output().ok()?;
+    let version = parse(&String::from_utf8_lossy(&output.stdout))?;
+    cache.insert(
+        qemu.to_string(),
+        Cached {
+            size: metadata.size(),
+            mtime: metadata.mtime(),
+            version: version.clone(),
+        },
+    );
+    if let (Some(path), Ok(contents)) = (path, toml::to_string(&cache)) {
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let _ = fs::write(&path, contents);
+    }
+    Some(version)
+}
+
+fn major_minor(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split('.').map(|part| part.parse::<u32>().ok());
+    Some((parts.next()??, parts.next().flatten().unwrap_or(0)))
+}
+
+// Refuse to run with a qemu older than the target, the cpu properties or
+// the options used need. A qemu which doesn't say its version is trusted.
+pub fn check(qemu: &str, target: &str, cpu: &str, options: &[&str]) {
+    let version = match version(qemu) {
+        Some(version) => version,
+        None => return,
+    };
+    debug!("{} is version {}", qemu, version);
+    let installed = match major_minor(&version) {
+        Some(installed) => installed,
+        None => return,
+    };
+
+    let mut needed: Vec<(String, (u32, u32))> = TARGETS
+        .iter()
+        .filter(|(name, _)| *name == target)
+        .map(|(name, version)| (format!("{} emulation", name), *version))
+        .collect();
+    needed.extend(
+        OPTIONS
+            .iter()
+            .filter(|(name, _)| options.contains(name))
+            .map(|(name, version)| (name.to_string(), *version)),
+    );
+    for property in cpu.split(',').skip(1) {
+        if let Some(name) = property.strip_suffix("=true") {
+            if let Some((_, version)) = CPU_PROPERTIES.iter().find(|(known, _)| *known == name) {
+                needed.push((format!("the {} extension", name), *version));
+            }
+        }
+    }
+
+    if let Some((what, (major, minor))) = needed
+        .into_iter()
+        .filter(|(_, required)| installed < *required)
+        .max_by_key(|(_, required)| *required)
+    {
+        panic!(
+            "{} is qemu {}, {} needs qemu {}.{} or newer. Install a newer qemu-user or pass --qemu.",
+            qemu, version, what, major, minor
+        );
+    }
+}